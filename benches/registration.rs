@@ -0,0 +1,61 @@
+use criterion::{
+    criterion_group,
+    criterion_main,
+    Criterion,
+};
+use strata::operator::{
+    Layer,
+    Method,
+    Operation,
+    Position,
+    View,
+    buffer::Image,
+};
+
+// Renders a field of gaussian stars as seen from a frame shifted by the given offset.
+fn field(width: u32, height: u32, dx: f32, dy: f32) -> Image {
+    let mut seed: u32 = 1;
+    let mut random = || {
+        seed = seed.wrapping_mul(1664525).wrapping_add(1013904223);
+        (seed >> 8) as f32 / 16777216.0
+    };
+    let stars = (0..200)
+        .map(|_| (random() * width as f32 * 1.5, random() * height as f32 * 1.5, 0.05 + random() * 0.5))
+        .collect::<Vec<(f32, f32, f32)>>();
+
+    let mut image = Image::new(width, height, 1);
+    for (x, y, flux) in stars {
+        let (cx, cy) = (x - dx, y - dy);
+        for py in (cy as i32 - 5).max(0)..(cy as i32 + 6).min(height as i32) {
+            for px in (cx as i32 - 5).max(0)..(cx as i32 + 6).min(width as i32) {
+                let distance = (px as f32 - cx).powi(2) + (py as f32 - cy).powi(2);
+                image.get_pixel_mut(px as u32, py as u32)[0] += flux * (-distance / 4.5).exp();
+            }
+        }
+    }
+    image
+}
+
+fn registration(c: &mut Criterion) {
+    let view = View {
+        layers: vec![
+            Layer::new(field(640, 480, 0.0, 0.0)),
+            Layer::new(field(640, 480, 37.4, 21.7)),
+        ],
+        ..View::default()
+    };
+
+    let mut group = c.benchmark_group("registration");
+    group.sample_size(10);
+    for (name, method) in &[("difference", Method::Difference), ("phase", Method::Phase)] {
+        group.bench_function(*name, |b| b.iter(|| Position::new()
+            .method(*method)
+            .apply(view.clone())
+            .unwrap()
+        ));
+    }
+    group.finish();
+}
+
+criterion_group!(benches, registration);
+criterion_main!(benches);
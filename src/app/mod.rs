@@ -21,7 +21,7 @@ use std::{
     env,
     process::exit,
 };
-use crate::operator::{
+use strata::operator::{
     run,
     error,
 };
@@ -80,7 +80,7 @@ impl SavePathOptions {
             if parent.is_dir() {
                 if let Some(extension) = path.extension() {
                     let extension = extension.to_string_lossy();
-                    if extension == "jpg" {
+                    if ["jpg", "png", "fits", "fit", "fts"].contains(&extension.as_ref()) {
                         SavePathOptions::Ok
                     } else {
                         SavePathOptions::InvalidExtension
@@ -263,7 +263,7 @@ impl Application for App {
                                             Text::new("Valid path.")
                                                 .color(Color::from([0.3, 0.7, 0.3])),
                                         SavePathOptions::InvalidExtension => 
-                                            Text::new("Only \".jpg\" and \".png\" file types are allowed.")
+                                            Text::new("Only \".jpg\", \".png\" and \".fits\" file types are allowed.")
                                                 .color(Color::from([0.7, 0.3, 0.3])),
                                         SavePathOptions::NotFound => 
                                             Text::new("Parent directory does not exist.")
@@ -0,0 +1,358 @@
+use clap::{
+    App,
+    Arg,
+    crate_version,
+};
+use std::{
+    path::PathBuf,
+    process::exit,
+};
+use strata::operator::{
+    build,
+    Options,
+    fits::Depth,
+    transform::Model,
+    resample::Kernel,
+    cfa::Pattern,
+    Algorithm,
+    Method,
+    Reference,
+    Pairing,
+    strategy::{
+        Mode,
+        Integration,
+    },
+    error::{
+        Error,
+        ErrorKind,
+    },
+};
+
+// Exit codes returned by the command line interface.
+const EXIT_SUCCESS: i32 = 0;
+const EXIT_USAGE: i32 = 2;
+const EXIT_IO: i32 = 3;
+const EXIT_IMAGE: i32 = 4;
+const EXIT_INPUT: i32 = 5;
+
+fn exit_code(error: &Error) -> i32 {
+    match error.kind() {
+        ErrorKind::Io => EXIT_IO,
+        ErrorKind::Image => EXIT_IMAGE,
+        ErrorKind::Input => EXIT_INPUT,
+    }
+}
+
+// Accepts numbers between 0 and 1.
+fn fraction(value: String) -> Result<(), String> {
+    match value.parse::<f32>() {
+        Ok(value) if (0.0..=1.0).contains(&value) => Ok(()),
+        _ => Err(format!("\"{}\" is not a number between 0 and 1", value))
+    }
+}
+
+// Accepts numbers above 0 up to 1.
+fn pixfrac(value: String) -> Result<(), String> {
+    match value.parse::<f32>() {
+        Ok(value) if value > 0.0 && value <= 1.0 => Ok(()),
+        _ => Err(format!("\"{}\" is not a number above 0 and up to 1", value))
+    }
+}
+
+// Accepts percentages above 0 up to 100.
+fn percentage(value: String) -> Result<(), String> {
+    match value.parse::<f32>() {
+        Ok(value) if value > 0.0 && value <= 100.0 => Ok(()),
+        _ => Err(format!("\"{}\" is not a percentage above 0 and up to 100", value))
+    }
+}
+
+// Accepts positive numbers.
+fn threshold(value: String) -> Result<(), String> {
+    match value.parse::<f32>() {
+        Ok(value) if value > 0.0 => Ok(()),
+        _ => Err(format!("\"{}\" is not a positive number", value))
+    }
+}
+
+// Runs the pipeline headless with the given command line arguments and exits the process.
+pub fn run() -> ! {
+    let matches = App::new("strata")
+        .version(crate_version!())
+        .about("Image merging application intended for astrophotography.")
+        .arg(Arg::with_name("input")
+            .help("Directory containing the input images")
+            .required(true)
+            .index(1))
+        .arg(Arg::with_name("output")
+            .help("Path of the resulting image")
+            .required(true)
+            .index(2))
+        .arg(Arg::with_name("preview")
+            .long("preview")
+            .help("Downscales the input images for a fast preview"))
+        .arg(Arg::with_name("skip-invalid")
+            .long("skip-invalid")
+            .help("Skips input files that cannot be read instead of aborting"))
+        .arg(Arg::with_name("fits-scale")
+            .long("fits-scale")
+            .takes_value(true)
+            .validator(threshold)
+            .help("Divides floating point FITS data by this factor, such as 65535 for values in ADU, instead of taking it as normalized to one"))
+        .arg(Arg::with_name("bias")
+            .long("bias")
+            .takes_value(true)
+            .help("Directory containing bias frames"))
+        .arg(Arg::with_name("dark")
+            .long("dark")
+            .takes_value(true)
+            .help("Directory containing dark frames"))
+        .arg(Arg::with_name("flat")
+            .long("flat")
+            .takes_value(true)
+            .help("Directory containing flat frames"))
+        .arg(Arg::with_name("dark-flat")
+            .long("dark-flat")
+            .takes_value(true)
+            .help("Directory containing dark flat frames"))
+        .arg(Arg::with_name("masters")
+            .long("masters")
+            .takes_value(true)
+            .help("Directory in which master calibration frames are cached"))
+        .arg(Arg::with_name("scale-dark")
+            .long("scale-dark")
+            .requires("bias")
+            .help("Scales the master dark to the exposure time and temperature of each frame, requires bias frames"))
+        .arg(Arg::with_name("cosmetic")
+            .long("cosmetic")
+            .help("Detects and corrects hot and cold pixels"))
+        .arg(Arg::with_name("cosmetic-dark")
+            .long("cosmetic-dark")
+            .takes_value(true)
+            .requires("cosmetic")
+            .help("Master dark FITS file used to find additional hot pixels"))
+        .arg(Arg::with_name("defects")
+            .long("defects")
+            .takes_value(true)
+            .requires("cosmetic")
+            .help("Defect map file listing the coordinates \"x y\" of one defective pixel per line"))
+        .arg(Arg::with_name("debayer")
+            .long("debayer")
+            .takes_value(true)
+            .possible_values(&["superpixel", "bilinear", "vng", "ahd"])
+            .default_value("bilinear")
+            .help("Demosaicing algorithm for one-shot-color sensor data"))
+        .arg(Arg::with_name("pattern")
+            .long("pattern")
+            .takes_value(true)
+            .possible_values(&["RGGB", "BGGR", "GRBG", "GBRG"])
+            .help("Overrides the Bayer pattern of the input files"))
+        .arg(Arg::with_name("min-stars")
+            .long("min-stars")
+            .takes_value(true)
+            .validator(|value| value.parse::<usize>().map(|_| ()).map_err(|error| error.to_string()))
+            .help("Rejects frames with fewer detected stars, such as frames taken through clouds"))
+        .arg(Arg::with_name("max-fwhm")
+            .long("max-fwhm")
+            .takes_value(true)
+            .validator(threshold)
+            .help("Rejects frames whose stars have a larger median FWHM in pixels, such as blurred or defocused frames"))
+        .arg(Arg::with_name("max-eccentricity")
+            .long("max-eccentricity")
+            .takes_value(true)
+            .validator(fraction)
+            .help("Rejects frames whose stars have a larger median eccentricity, such as frames trailed by wind or bad tracking"))
+        .arg(Arg::with_name("max-background")
+            .long("max-background")
+            .takes_value(true)
+            .validator(threshold)
+            .help("Rejects frames with a brighter background, relative to the white level"))
+        .arg(Arg::with_name("max-noise")
+            .long("max-noise")
+            .takes_value(true)
+            .validator(threshold)
+            .help("Rejects frames with more background noise, relative to the white level"))
+        .arg(Arg::with_name("min-snr")
+            .long("min-snr")
+            .takes_value(true)
+            .validator(threshold)
+            .help("Rejects frames whose stars have a lower median ratio of their peak to the background noise"))
+        .arg(Arg::with_name("keep-best")
+            .long("keep-best")
+            .takes_value(true)
+            .validator(percentage)
+            .help("Keeps only the given percentage of frames with the most and smallest stars"))
+        .arg(Arg::with_name("registration")
+            .long("registration")
+            .takes_value(true)
+            .possible_values(&["difference", "phase", "stars", "none"])
+            .default_value("difference")
+            .help("Registration method, minimizing image differences, correlating phases or matching star triangles, or none to leave the images in place"))
+        .arg(Arg::with_name("reference")
+            .long("reference")
+            .takes_value(true)
+            .default_value("auto")
+            .help("Path, file name or index \"#N\" counting from 0 of the frame all other frames are aligned to, by default \"auto\", the frame with the most and smallest stars"))
+        .arg(Arg::with_name("pairing")
+            .long("pairing")
+            .takes_value(true)
+            .possible_values(&["all", "reference", "sequential", "similar"])
+            .default_value("all")
+            .help("Pairs of frames that are matched, all pairs or each frame with the reference, its neighbours in time or the frames with the closest tiny thumbnails"))
+        .arg(Arg::with_name("neighbours")
+            .long("neighbours")
+            .takes_value(true)
+            .validator(|value| value.parse::<usize>().map(|_| ()).map_err(|error| error.to_string()))
+            .help("Number of frames each frame is matched with for sequential and similar pairing, 3 by default"))
+        .arg(Arg::with_name("meridian-flip")
+            .long("meridian-flip")
+            .help("Also searches each frame rotated by 180 degrees when registering by differences or phases, star registration finds rotations by itself"))
+        .arg(Arg::with_name("registration-cache")
+            .long("registration-cache")
+            .takes_value(true)
+            .help("File in which the registration is kept and reused by later runs with the same frames and settings"))
+        .arg(Arg::with_name("transform")
+            .long("transform")
+            .takes_value(true)
+            .possible_values(&["translation", "similarity", "affine", "homography"])
+            .default_value("similarity")
+            .help("Degrees of freedom of the transforms found by star registration, homography requires --registration stars"))
+        .arg(Arg::with_name("min-overlap")
+            .long("min-overlap")
+            .takes_value(true)
+            .validator(fraction)
+            .help("Fraction of the smaller image two images must share to be matched, 0.1 by default"))
+        .arg(Arg::with_name("min-confidence")
+            .long("min-confidence")
+            .takes_value(true)
+            .validator(fraction)
+            .help("Confidence between 0 and 1 below which matches are not used, 0.2 by default"))
+        .arg(Arg::with_name("interpolation")
+            .long("interpolation")
+            .takes_value(true)
+            .possible_values(&["nearest", "bilinear", "bicubic", "lanczos3"])
+            .default_value("lanczos3")
+            .help("Interpolation used to resample the aligned images"))
+        .arg(Arg::with_name("join")
+            .long("join")
+            .takes_value(true)
+            .possible_values(&["mosaic", "multiband", "stack", "weighted", "maximum", "minimum", "drizzle"])
+            .default_value("mosaic")
+            .help("Blends the images as a mosaic, optionally band by band, stacks them with outlier rejection or weighted by their noise, keeps the brightest or darkest value of each pixel, or drizzles them onto a finer grid"))
+        .arg(Arg::with_name("seam")
+            .long("seam")
+            .help("Cuts the overlaps of multi-band blended images along paths that avoid stars"))
+        .arg(Arg::with_name("integration")
+            .long("integration")
+            .takes_value(true)
+            .possible_values(&["average", "median", "kappa-sigma", "winsorized-sigma", "linear-fit"])
+            .default_value("kappa-sigma")
+            .help("Combination of the values of each pixel when stacking"))
+        .arg(Arg::with_name("rejection-low")
+            .long("rejection-low")
+            .takes_value(true)
+            .validator(threshold)
+            .help("Threshold below the center at which values are rejected when stacking, 3 by default"))
+        .arg(Arg::with_name("rejection-high")
+            .long("rejection-high")
+            .takes_value(true)
+            .validator(threshold)
+            .help("Threshold above the center at which values are rejected when stacking, 3 by default"))
+        .arg(Arg::with_name("drizzle-scale")
+            .long("drizzle-scale")
+            .takes_value(true)
+            .validator(threshold)
+            .help("Resolution of the drizzled image relative to the reference, 2 by default"))
+        .arg(Arg::with_name("pixfrac")
+            .long("pixfrac")
+            .takes_value(true)
+            .validator(pixfrac)
+            .help("Size of the drops relative to the pixels when drizzling, 0.7 by default"))
+        .arg(Arg::with_name("depth")
+            .long("depth")
+            .takes_value(true)
+            .possible_values(&["16", "32"])
+            .default_value("32")
+            .help("Bit depth of FITS output, 16-bit integers or 32-bit floats"))
+        .arg(Arg::with_name("overwrite")
+            .long("overwrite")
+            .help("Overwrites the output file if it already exists"))
+        .get_matches_safe()
+        .unwrap_or_else(|error| match error.kind {
+            clap::ErrorKind::HelpDisplayed | clap::ErrorKind::VersionDisplayed => error.exit(),
+            // Clap exits with 1 by itself, invalid arguments are reported with the usage code.
+            _ => {
+                eprintln!("{}", error.message);
+                exit(EXIT_USAGE);
+            }
+        });
+
+    let input = PathBuf::from(matches.value_of("input").unwrap());
+    let output = PathBuf::from(matches.value_of("output").unwrap());
+
+    if !input.is_dir() {
+        eprintln!("Input path \"{}\" is not a directory.", input.display());
+        exit(EXIT_USAGE);
+    }
+    if output.exists() && !matches.is_present("overwrite") {
+        eprintln!("Output file \"{}\" already exists, use --overwrite to replace it.", output.display());
+        exit(EXIT_USAGE);
+    }
+    if matches.value_of("transform") == Some("homography") && matches.value_of("registration") != Some("stars") {
+        eprintln!("Homography transforms are only found by star registration, use --registration stars.");
+        exit(EXIT_USAGE);
+    }
+
+    let options = Options {
+        preview: matches.is_present("preview"),
+        skip_invalid: matches.is_present("skip-invalid"),
+        fits_scale: matches.value_of("fits-scale").and_then(|value| value.parse().ok()),
+        bias: matches.value_of("bias").map(PathBuf::from),
+        dark: matches.value_of("dark").map(PathBuf::from),
+        flat: matches.value_of("flat").map(PathBuf::from),
+        dark_flat: matches.value_of("dark-flat").map(PathBuf::from),
+        masters: matches.value_of("masters").map(PathBuf::from),
+        scale_dark: matches.is_present("scale-dark"),
+        cosmetic: matches.is_present("cosmetic"),
+        cosmetic_dark: matches.value_of("cosmetic-dark").map(PathBuf::from),
+        defects: matches.value_of("defects").map(PathBuf::from),
+        debayer: matches.value_of("debayer").and_then(Algorithm::parse).unwrap_or_default(),
+        pattern: matches.value_of("pattern").and_then(Pattern::parse),
+        registration: matches.value_of("registration").and_then(Method::parse).unwrap_or_default(),
+        reference: matches.value_of("reference").map(|value| Reference::parse(value, &input)).unwrap_or_default(),
+        pairing: matches.value_of("pairing").and_then(Pairing::parse).unwrap_or_default(),
+        neighbours: matches.value_of("neighbours").and_then(|value| value.parse().ok()),
+        meridian_flip: matches.is_present("meridian-flip"),
+        registration_cache: matches.value_of("registration-cache").map(PathBuf::from),
+        min_stars: matches.value_of("min-stars").and_then(|value| value.parse().ok()),
+        max_fwhm: matches.value_of("max-fwhm").and_then(|value| value.parse().ok()),
+        max_eccentricity: matches.value_of("max-eccentricity").and_then(|value| value.parse().ok()),
+        max_background: matches.value_of("max-background").and_then(|value| value.parse().ok()),
+        max_noise: matches.value_of("max-noise").and_then(|value| value.parse().ok()),
+        min_snr: matches.value_of("min-snr").and_then(|value| value.parse().ok()),
+        keep_best: matches.value_of("keep-best").and_then(|value| value.parse().ok()),
+        transform: matches.value_of("transform").and_then(Model::parse).unwrap_or_default(),
+        min_overlap: matches.value_of("min-overlap").and_then(|value| value.parse().ok()),
+        min_confidence: matches.value_of("min-confidence").and_then(|value| value.parse().ok()),
+        interpolation: matches.value_of("interpolation").and_then(Kernel::parse).unwrap_or_default(),
+        join: matches.value_of("join").and_then(Mode::parse).unwrap_or_default(),
+        integration: matches.value_of("integration").and_then(Integration::parse).unwrap_or_default(),
+        seam: matches.is_present("seam"),
+        rejection_low: matches.value_of("rejection-low").and_then(|value| value.parse().ok()),
+        rejection_high: matches.value_of("rejection-high").and_then(|value| value.parse().ok()),
+        drizzle_scale: matches.value_of("drizzle-scale").and_then(|value| value.parse().ok()),
+        pixfrac: matches.value_of("pixfrac").and_then(|value| value.parse().ok()),
+        depth: match matches.value_of("depth") {
+            Some("16") => Depth::Integer,
+            _ => Depth::Float,
+        },
+    };
+
+    match build(input, output, &options).run() {
+        Ok(()) => exit(EXIT_SUCCESS),
+        Err(error) => {
+            eprintln!("Error: {}", error);
+            exit(exit_code(&error));
+        }
+    }
+}
@@ -0,0 +1,6 @@
+// Strata is an image merging library intended for astrophotography.
+//
+// The stacking pipeline lives in the operator module: an Operator applies a chain of Operations to
+// a View of layers.
+
+pub mod operator;
@@ -1,15 +1,23 @@
-#![windows_subsystem = "windows"]
+#![cfg_attr(feature = "gui", windows_subsystem = "windows")]
 
+#[cfg(feature = "gui")]
 mod app;
-mod operator;
+mod cli;
 
+#[cfg(feature = "gui")]
 use iced::{
     Settings,
     window,
     Application,
 };
 
+#[cfg(feature = "gui")]
 fn main() {
+    // Any command line arguments switch to the headless interface.
+    if std::env::args_os().len() > 1 {
+        cli::run();
+    }
+
     println!("Starting application.");
     app::App::run(Settings {
         window: window::Settings {
@@ -21,4 +29,9 @@ fn main() {
         ..
         Default::default()
     });
-}
\ No newline at end of file
+}
+
+#[cfg(not(feature = "gui"))]
+fn main() {
+    cli::run();
+}
@@ -0,0 +1,227 @@
+use super::transform::{
+    Model,
+    Point,
+    Transform,
+};
+use nalgebra::{
+    DMatrix,
+    DVector,
+    Matrix3,
+};
+
+const ITERATIONS: usize = 20;
+// Residual in pixels at which the robust loss halves the weight of a pair.
+const LOSS_SCALE: f64 = 2.0;
+// Damping of the Levenberg-Marquardt steps relative to the diagonal of the normal matrix.
+const DAMPING: f64 = 1e-3;
+
+// A pairwise constraint: the transform maps the coordinates of the second layer to the first.
+pub struct Edge {
+    pub first: usize,
+    pub second: usize,
+    pub transform: Transform,
+}
+
+// The disagreement of an edge with the adjusted transforms.
+pub struct Residual {
+    // Root mean square distance of the control points in pixels.
+    pub distance: f32,
+    // Weight given to the edge by the robust loss, between 0 and 1.
+    pub weight: f32,
+}
+
+// Number of parameters of the model, which map to the eight parameters of a homography
+// [1 + p0, p1, p2; p3, 1 + p4, p5; p6, p7, 1] as returned by this function.
+fn parameters(model: Model) -> Vec<Vec<(usize, f64)>> {
+    match model {
+        Model::Translation => vec![vec![(2, 1.0)], vec![(5, 1.0)]],
+        Model::Similarity => vec![vec![(0, 1.0), (4, 1.0)], vec![(1, -1.0), (3, 1.0)], vec![(2, 1.0)], vec![(5, 1.0)]],
+        Model::Affine => (0..6).map(|i| vec![(i, 1.0)]).collect(),
+        Model::Homography => (0..8).map(|i| vec![(i, 1.0)]).collect(),
+    }
+}
+
+// The derivatives of a point moved by an update near identity, for x and y.
+fn derivatives(point: &Point, model: Model) -> (Vec<f64>, Vec<f64>) {
+    let (x, y) = (point.x as f64, point.y as f64);
+    let dx = [x, y, 1.0, 0.0, 0.0, 0.0, -x * x, -x * y];
+    let dy = [0.0, 0.0, 0.0, x, y, 1.0, -x * y, -y * y];
+
+    parameters(model)
+        .iter()
+        .map(|parameter| parameter
+            .iter()
+            .fold((0.0, 0.0), |(a, b), &(i, factor)| (a + dx[i] * factor, b + dy[i] * factor))
+        )
+        .unzip()
+}
+
+fn update(values: &[f64], model: Model) -> Option<Transform> {
+    let mut p = [0.0; 8];
+    for (value, parameter) in values.iter().zip(parameters(model)) {
+        for (i, factor) in parameter {
+            p[i] += value * factor;
+        }
+    }
+
+    Transform::from_matrix(Matrix3::new(
+        1.0 + p[0], p[1], p[2],
+        p[3], 1.0 + p[4], p[5],
+        p[6], p[7], 1.0,
+    ).map(|value| value as f32))
+}
+
+// Control points spread over a layer of the given size.
+fn control_points(width: u32, height: u32) -> Vec<Point> {
+    (0..3)
+        .flat_map(|y| (0..3).map(move |x| Point::new(
+            x as f32 * (width as f32 - 1.0) / 2.0,
+            y as f32 * (height as f32 - 1.0) / 2.0
+        )))
+        .collect()
+}
+
+// Measures every edge at its control points with the current transforms.
+fn residuals(transforms: &[Transform], edges: &[Edge], sizes: &[(u32, u32)]) -> Vec<f64> {
+    edges
+        .iter()
+        .map(|edge| {
+            let points = control_points(sizes[edge.second].0, sizes[edge.second].1);
+            let sum = points
+                .iter()
+                .map(|point| {
+                    let a = transforms[edge.first].apply(&edge.transform.apply(point));
+                    let b = transforms[edge.second].apply(point);
+                    (a - b).norm_squared() as f64
+                })
+                .sum::<f64>();
+            (sum / points.len() as f64).sqrt()
+        })
+        .collect()
+}
+
+// The Cauchy loss gives little weight to pairs that disagree strongly with the others.
+fn weight(residual: f64) -> f64 {
+    1.0 / (1.0 + (residual / LOSS_SCALE).powi(2))
+}
+
+// Adjusts the transforms of all layers to agree with all edges at once in the least squares sense,
+// starting from the given transforms. Fixed layers keep their transform, one of them in each
+// connected group of layers anchors the solution. The residuals of the edges are returned.
+pub fn adjust(transforms: &mut [Transform], fixed: &[bool], edges: &[Edge], sizes: &[(u32, u32)], model: Model) -> Vec<Residual> {
+    let count = parameters(model).len();
+    let mut indices = vec![None; transforms.len()];
+    let mut free = 0;
+    for (i, index) in indices.iter_mut().enumerate() {
+        if !fixed[i] {
+            *index = Some(free);
+            free += 1;
+        }
+    }
+
+    if free > 0 {
+        for _ in 0..ITERATIONS {
+            let size = free * count;
+            let mut normal = DMatrix::<f64>::zeros(size, size);
+            let mut gradient = DVector::<f64>::zeros(size);
+            let weights = residuals(transforms, edges, sizes)
+                .into_iter()
+                .map(weight)
+                .collect::<Vec<f64>>();
+
+            for (edge, weight) in edges.iter().zip(weights) {
+                for point in control_points(sizes[edge.second].0, sizes[edge.second].1) {
+                    let a = transforms[edge.first].apply(&edge.transform.apply(&point));
+                    let b = transforms[edge.second].apply(&point);
+                    let residual = [(a.x - b.x) as f64, (a.y - b.y) as f64];
+
+                    // The residual moves with the first layer and against the second.
+                    let blocks = [
+                        (indices[edge.first], derivatives(&a, model), 1.0),
+                        (indices[edge.second], derivatives(&b, model), -1.0),
+                    ];
+                    for (row, (dx, dy), sign) in blocks.iter() {
+                        let row = match row {
+                            Some(row) => row * count,
+                            None => continue
+                        };
+                        for k in 0..count {
+                            gradient[row + k] += weight * sign * (dx[k] * residual[0] + dy[k] * residual[1]);
+                        }
+                        for (column, (ex, ey), other) in blocks.iter() {
+                            let column = match column {
+                                Some(column) => column * count,
+                                None => continue
+                            };
+                            for k in 0..count {
+                                for l in 0..count {
+                                    normal[(row + k, column + l)] += weight * sign * other * (dx[k] * ex[l] + dy[k] * ey[l]);
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+
+            for i in 0..size {
+                normal[(i, i)] += DAMPING * normal[(i, i)] + 1e-12;
+            }
+            let step = match normal.clone().cholesky() {
+                Some(cholesky) => cholesky.solve(&-gradient),
+                None => match normal.lu().solve(&-gradient) {
+                    Some(step) => step,
+                    None => break
+                }
+            };
+
+            for (i, transform) in transforms.iter_mut().enumerate() {
+                if let Some(index) = indices[i] {
+                    if let Some(update) = update(&step.as_slice()[(index * count)..((index + 1) * count)], model) {
+                        *transform = transform.then(&update);
+                    }
+                }
+            }
+
+            if step.amax() < 1e-6 {
+                break;
+            }
+        }
+    }
+
+    residuals(transforms, edges, sizes)
+        .into_iter()
+        .map(|distance| Residual {
+            distance: distance as f32,
+            weight: weight(distance) as f32,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn inconsistent_pairs_are_outweighed() {
+        // Frame 1 is 10 pixels right of frame 0 and frame 2 is 8 pixels below frame 1, but the pair
+        // of frames 0 and 2 was matched far off.
+        let edges = vec![
+            Edge { first: 0, second: 1, transform: Transform::translation(10.0, 0.0) },
+            Edge { first: 1, second: 2, transform: Transform::translation(0.0, 8.0) },
+            Edge { first: 0, second: 2, transform: Transform::translation(60.0, -40.0) },
+        ];
+        let mut transforms = vec![Transform::translation(0.0, 0.0); 3];
+        let residuals = adjust(&mut transforms, &[true, false, false], &edges, &[(100, 80); 3], Model::Similarity);
+
+        // The robust loss keeps a slight pull of the wrong pair, far below a pixel.
+        let corner = Point::new(99.0, 79.0);
+        for (transform, expected) in transforms.iter().zip(&[(0.0, 0.0), (10.0, 0.0), (10.0, 8.0)]) {
+            for point in &[Point::zeros(), corner] {
+                let moved = transform.apply(point) - point;
+                assert!((moved - Point::new(expected.0, expected.1)).norm() < 0.2, "moved by {:?} instead of {:?}", moved, expected);
+            }
+        }
+        assert!(residuals[0].distance < 0.2 && residuals[1].distance < 0.2);
+        assert!(residuals[2].weight < 0.01);
+    }
+}
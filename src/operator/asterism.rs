@@ -0,0 +1,304 @@
+use super::{
+    stars::Star,
+    transform::{
+        Model,
+        Point,
+        Transform,
+    },
+};
+use std::cmp::Ordering;
+
+// Number of brightest stars of each frame that form triangles.
+const TRIANGLE_STARS: usize = 25;
+// Number of brightest stars of each frame that are matched once the transform is known.
+const MATCH_STARS: usize = 200;
+// Maximum distance of two triangles in the space of their side ratios.
+const TOLERANCE: f32 = 0.01;
+// Maximum distance in pixels of a transformed star from its match.
+const INLIER_DISTANCE: f32 = 2.0;
+const ITERATIONS: usize = 500;
+// Minimum number of matched stars for a transform to be accepted.
+const MINIMUM_MATCHES: usize = 3;
+// Maximum factor by which a transform may change the area of the second frame anywhere relative to
+// its center. Homographies fitted to a few stars in one corner can be nearly singular elsewhere.
+const MAXIMUM_DISTORTION: f32 = 4.0;
+
+// The result of registering two frames by their stars.
+#[derive(Clone, Copy, Debug)]
+pub struct Match {
+    // Maps the coordinates of the second frame to those of the first.
+    pub transform: Transform,
+    // Number of stars inside the overlap of both frames that were considered and that were matched.
+    pub stars: usize,
+    pub matched: usize,
+    // Root mean square distance of the matched stars in pixels.
+    pub residual: f32,
+}
+
+// A triangle of stars, described by the ratios of its sides which do not change under
+// translation, rotation and scaling.
+struct Triangle {
+    // Indices of the stars, ordered by the length of the opposite side.
+    vertices: [usize; 3],
+    ratios: (f32, f32),
+    // The sign of the winding direction, mirrored frames are not matched.
+    clockwise: bool,
+}
+
+fn point(star: &Star) -> Point {
+    Point::new(star.x, star.y)
+}
+
+fn triangles(stars: &[Star]) -> Vec<Triangle> {
+    let stars = &stars[..stars.len().min(TRIANGLE_STARS)];
+    let mut triangles = Vec::new();
+
+    for i in 0..stars.len() {
+        for j in (i + 1)..stars.len() {
+            for k in (j + 1)..stars.len() {
+                let points = [point(&stars[i]), point(&stars[j]), point(&stars[k])];
+                // Side n is opposite of vertex n.
+                let sides = [
+                    (points[1] - points[2]).norm(),
+                    (points[0] - points[2]).norm(),
+                    (points[0] - points[1]).norm(),
+                ];
+                let mut order = [0, 1, 2];
+                order.sort_by(|&a, &b| sides[a].partial_cmp(&sides[b]).unwrap_or(Ordering::Equal));
+                let (a, b, c) = (sides[order[0]], sides[order[1]], sides[order[2]]);
+
+                // Small and very flat triangles have unstable ratios.
+                if c < 10.0 * INLIER_DISTANCE || a / c < 0.1 {
+                    continue;
+                }
+
+                let indices = [i, j, k];
+                let (u, v) = (points[order[1]] - points[order[0]], points[order[2]] - points[order[0]]);
+                triangles.push(Triangle {
+                    vertices: [indices[order[0]], indices[order[1]], indices[order[2]]],
+                    ratios: (a / c, b / c),
+                    clockwise: u.x * v.y - u.y * v.x > 0.0,
+                });
+            }
+        }
+    }
+
+    triangles.sort_by(|a, b| a.ratios.0.partial_cmp(&b.ratios.0).unwrap_or(Ordering::Equal));
+    triangles
+}
+
+// Finds likely star correspondences by letting similar triangles vote for the pairs of their vertices.
+fn correspondences(first: &[Star], second: &[Star]) -> Vec<(usize, usize)> {
+    let (t1, t2) = (triangles(first), triangles(second));
+    let (n1, n2) = (first.len().min(TRIANGLE_STARS), second.len().min(TRIANGLE_STARS));
+    let mut votes = vec![0; n1 * n2];
+
+    for triangle in &t1 {
+        let start = t2
+            .binary_search_by(|other| if other.ratios.0 < triangle.ratios.0 - TOLERANCE {
+                Ordering::Less
+            } else {
+                Ordering::Greater
+            })
+            .unwrap_or_else(|i| i);
+
+        let best = t2[start..]
+            .iter()
+            .take_while(|other| other.ratios.0 <= triangle.ratios.0 + TOLERANCE)
+            .filter(|other| other.clockwise == triangle.clockwise)
+            .map(|other| (other, (other.ratios.0 - triangle.ratios.0).hypot(other.ratios.1 - triangle.ratios.1)))
+            .filter(|(_, distance)| *distance < TOLERANCE)
+            .min_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(Ordering::Equal));
+
+        if let Some((other, _)) = best {
+            for (a, b) in triangle.vertices.iter().zip(other.vertices.iter()) {
+                votes[a * n2 + b] += 1;
+            }
+        }
+    }
+
+    // A pair is kept if each star received most of its votes for the other.
+    let mut pairs = (0..n1)
+        .filter_map(|a| {
+            let b = (0..n2).max_by_key(|&b| votes[a * n2 + b])?;
+            let count = votes[a * n2 + b];
+            let reciprocal = (0..n1).all(|other| other == a || votes[other * n2 + b] < count);
+            if count > 0 && reciprocal {
+                Some((a, b, count))
+            } else {
+                None
+            }
+        })
+        .collect::<Vec<(usize, usize, usize)>>();
+    pairs.sort_by(|a, b| b.2.cmp(&a.2));
+
+    pairs.into_iter().map(|(a, b, _)| (a, b)).collect()
+}
+
+// Pairs every star of the second frame with the closest star of the first frame after transforming it.
+fn matches(first: &[Star], second: &[Star], transform: &Transform) -> Vec<(Point, Point)> {
+    second
+        .iter()
+        .filter_map(|star| {
+            let transformed = transform.apply(&point(star));
+            first
+                .iter()
+                .map(|other| (point(other), (point(other) - transformed).norm()))
+                .filter(|(_, distance)| *distance < INLIER_DISTANCE)
+                .min_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(Ordering::Equal))
+                .map(|(other, _)| (other, point(star)))
+        })
+        .collect()
+}
+
+// Registers the second list of stars to the first one. Correspondences are found by matching
+// triangle asterisms and the transform of the given model is solved robustly with RANSAC, only
+// accepting transforms that are regular over the second frame. The sizes are those of the first and
+// second frame. Returns none if the frames do not share enough stars.
+pub fn register(first: &[Star], second: &[Star], sizes: [(u32, u32); 2], model: Model) -> Option<Match> {
+    let estimate = |pairs: &[(Point, Point)]| Transform::estimate(model, pairs)
+        .filter(|transform| transform.is_regular(sizes[1].0, sizes[1].1, MAXIMUM_DISTORTION));
+
+    let candidates = correspondences(first, second)
+        .into_iter()
+        .map(|(a, b)| (point(&first[a]), point(&second[b])))
+        .collect::<Vec<(Point, Point)>>();
+    if candidates.len() < MINIMUM_MATCHES.max(model.samples()) {
+        return None;
+    }
+
+    // A deterministic xorshift generator keeps the results reproducible.
+    let mut state: u32 = 0x9e3779b9;
+    let mut random = |n: usize| {
+        state ^= state << 13;
+        state ^= state >> 17;
+        state ^= state << 5;
+        state as usize % n
+    };
+
+    let inliers = |transform: &Transform| candidates
+        .iter()
+        .filter(|(a, b)| (transform.apply(b) - a).norm() < INLIER_DISTANCE)
+        .cloned()
+        .collect::<Vec<(Point, Point)>>();
+
+    let mut best = Vec::new();
+    for _ in 0..ITERATIONS {
+        let mut indices = Vec::with_capacity(model.samples());
+        while indices.len() < model.samples() {
+            let i = random(candidates.len());
+            if !indices.contains(&i) {
+                indices.push(i);
+            }
+        }
+        let sample = indices
+            .iter()
+            .map(|&i| candidates[i])
+            .collect::<Vec<(Point, Point)>>();
+
+        if let Some(transform) = estimate(&sample) {
+            let consensus = inliers(&transform);
+            if consensus.len() > best.len() {
+                best = consensus;
+            }
+        }
+    }
+    if best.len() < MINIMUM_MATCHES {
+        return None;
+    }
+
+    // The transform is refined on all stars that agree with the consensus.
+    let (first, second) = (&first[..first.len().min(MATCH_STARS)], &second[..second.len().min(MATCH_STARS)]);
+    let mut transform = estimate(&best)?;
+    let mut pairs = best;
+    for _ in 0..2 {
+        let refined = matches(first, second, &transform);
+        match estimate(&refined) {
+            Some(estimate) if refined.len() >= pairs.len() => {
+                transform = estimate;
+                pairs = refined;
+            },
+            _ => break
+        }
+    }
+
+    let residual = (pairs
+        .iter()
+        .map(|(a, b)| (transform.apply(b) - a).norm_squared())
+        .sum::<f32>() / pairs.len() as f32)
+        .sqrt();
+
+    // Stars outside the overlap cannot be matched, so only those inside count.
+    let inside = |stars: &[Star], size: (u32, u32), map: &dyn Fn(&Point) -> Point| stars
+        .iter()
+        .map(|star| map(&point(star)))
+        .filter(|point| point.x >= -0.5 && point.y >= -0.5 && point.x < size.0 as f32 - 0.5 && point.y < size.1 as f32 - 0.5)
+        .count();
+    let stars = inside(first, sizes[1], &|point| transform.apply_inverse(point))
+        .min(inside(second, sizes[0], &|point| transform.apply(point)));
+
+    Some(Match {
+        transform,
+        stars: stars.max(pairs.len()),
+        matched: pairs.len(),
+        residual,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SIZE: (u32, u32) = (400, 300);
+
+    // Stars scattered over a frame from a fixed seed, sorted by decreasing flux.
+    fn field() -> Vec<Star> {
+        let mut state = 12345u32;
+        let mut random = move || {
+            state = state.wrapping_mul(1664525).wrapping_add(1013904223);
+            (state >> 8) as f32 / (1 << 24) as f32
+        };
+        (0..80)
+            .map(|i| Star {
+                x: random() * SIZE.0 as f32,
+                y: random() * SIZE.1 as f32,
+                flux: 1000.0 - i as f32,
+                peak: 1.0,
+                fwhm: 2.5,
+                eccentricity: 0.1,
+            })
+            .collect()
+    }
+
+    // The stars as seen in a second frame whose coordinates the transform maps to the first.
+    fn seen(stars: &[Star], transform: &Transform) -> Vec<Star> {
+        stars
+            .iter()
+            .map(|star| {
+                let point = transform.apply_inverse(&point(star));
+                Star { x: point.x, y: point.y, ..*star }
+            })
+            .filter(|star| star.x >= 0.0 && star.y >= 0.0 && star.x < SIZE.0 as f32 && star.y < SIZE.1 as f32)
+            .collect()
+    }
+
+    fn assert_recovered(transform: Transform) {
+        let first = field();
+        let result = register(&first, &seen(&first, &transform), [SIZE, SIZE], Model::Similarity).unwrap();
+        assert!(result.matched >= 30, "{} stars matched", result.matched);
+        for &(x, y) in &[(0.0, 0.0), (400.0, 0.0), (0.0, 300.0), (400.0, 300.0)] {
+            let point = Point::new(x, y);
+            assert!((result.transform.apply(&point) - transform.apply(&point)).norm() < 0.01);
+        }
+    }
+
+    #[test]
+    fn rotated_and_shifted_stars_are_registered() {
+        assert_recovered(Transform::similarity(1.0, 0.2, 31.7, -12.4));
+    }
+
+    #[test]
+    fn flipped_stars_are_registered() {
+        assert_recovered(Transform::flip(SIZE.0, SIZE.1).then(&Transform::translation(5.3, -8.1)));
+    }
+}
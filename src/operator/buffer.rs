@@ -0,0 +1,186 @@
+use image::{
+    DynamicImage,
+    GenericImageView,
+    ImageBuffer,
+    Luma,
+    Rgb,
+    imageops::{
+        self,
+        FilterType,
+    },
+};
+
+// Linear floating point image data with interleaved channels, either mono or RGB.
+// Values are normalized such that one corresponds to the white point of the input.
+#[derive(Clone, Debug)]
+pub struct Image {
+    width: u32,
+    height: u32,
+    channels: usize,
+    data: Vec<f32>,
+}
+
+impl Image {
+    pub fn new(width: u32, height: u32, channels: usize) -> Image {
+        Image {
+            width,
+            height,
+            channels,
+            data: vec![0.0; width as usize * height as usize * channels],
+        }
+    }
+
+    pub fn from_raw(width: u32, height: u32, channels: usize, data: Vec<f32>) -> Image {
+        debug_assert_eq!(data.len(), width as usize * height as usize * channels);
+
+        Image {
+            width,
+            height,
+            channels,
+            data,
+        }
+    }
+
+    // Converts interleaved data with the given number of channels per pixel, picking
+    // the channels in the given order. Alpha channels are dropped this way.
+    fn convert<T: Copy>(width: u32, height: u32, data: &[T], stride: usize, order: &[usize], max: f32, f: fn(T) -> f32) -> Image {
+        Image {
+            width,
+            height,
+            channels: order.len(),
+            data: data
+                .chunks(stride)
+                .flat_map(|pixel| order.iter().map(move |&c| f(pixel[c]) / max))
+                .collect(),
+        }
+    }
+
+    // Converts a decoded image of any color type.
+    pub fn from_dynamic(image: &DynamicImage) -> Image {
+        let (width, height) = image.dimensions();
+        let (u8_max, u16_max) = (u8::max_value() as f32, u16::max_value() as f32);
+        let (mono, rgb, bgr) = (&[0][..], &[0, 1, 2][..], &[2, 1, 0][..]);
+
+        match image {
+            DynamicImage::ImageLuma8(image) => Image::convert(width, height, image.as_raw(), 1, mono, u8_max, f32::from),
+            DynamicImage::ImageLumaA8(image) => Image::convert(width, height, image.as_raw(), 2, mono, u8_max, f32::from),
+            DynamicImage::ImageRgb8(image) => Image::convert(width, height, image.as_raw(), 3, rgb, u8_max, f32::from),
+            DynamicImage::ImageRgba8(image) => Image::convert(width, height, image.as_raw(), 4, rgb, u8_max, f32::from),
+            DynamicImage::ImageBgr8(image) => Image::convert(width, height, image.as_raw(), 3, bgr, u8_max, f32::from),
+            DynamicImage::ImageBgra8(image) => Image::convert(width, height, image.as_raw(), 4, bgr, u8_max, f32::from),
+            DynamicImage::ImageLuma16(image) => Image::convert(width, height, image.as_raw(), 1, mono, u16_max, f32::from),
+            DynamicImage::ImageLumaA16(image) => Image::convert(width, height, image.as_raw(), 2, mono, u16_max, f32::from),
+            DynamicImage::ImageRgb16(image) => Image::convert(width, height, image.as_raw(), 3, rgb, u16_max, f32::from),
+            DynamicImage::ImageRgba16(image) => Image::convert(width, height, image.as_raw(), 4, rgb, u16_max, f32::from),
+        }
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    pub fn channels(&self) -> usize {
+        self.channels
+    }
+
+    pub fn is_mono(&self) -> bool {
+        self.channels == 1
+    }
+
+    pub fn as_raw(&self) -> &[f32] {
+        &self.data
+    }
+
+    pub fn as_raw_mut(&mut self) -> &mut [f32] {
+        &mut self.data
+    }
+
+    fn index(&self, x: u32, y: u32) -> usize {
+        (y as usize * self.width as usize + x as usize) * self.channels
+    }
+
+    pub fn get_pixel(&self, x: u32, y: u32) -> &[f32] {
+        let i = self.index(x, y);
+        &self.data[i..(i + self.channels)]
+    }
+
+    pub fn get_pixel_mut(&mut self, x: u32, y: u32) -> &mut [f32] {
+        let i = self.index(x, y);
+        &mut self.data[i..(i + self.channels)]
+    }
+
+    // Returns the value of a channel, mono pixels provide the same value for every channel.
+    pub fn get_channel(&self, x: u32, y: u32, channel: usize) -> f32 {
+        self.data[self.index(x, y) + channel.min(self.channels - 1)]
+    }
+
+    // Iterates through all pixels with their coordinates.
+    pub fn enumerate_pixels(&self) -> impl Iterator<Item = (u32, u32, &[f32])> {
+        let width = self.width;
+        self.data
+            .chunks(self.channels)
+            .enumerate()
+            .map(move |(i, pixel)| ((i % width as usize) as u32, (i / width as usize) as u32, pixel))
+    }
+
+    // Returns the image rotated by 180 degrees.
+    pub fn rotate180(&self) -> Image {
+        let data = self.data
+            .chunks(self.channels)
+            .rev()
+            .flatten()
+            .cloned()
+            .collect();
+
+        Image::from_raw(self.width, self.height, self.channels, data)
+    }
+
+    // Resizes the image to fit into the given dimensions, preserving the aspect ratio.
+    pub fn resize(&self, width: u32, height: u32, filter: FilterType) -> Image {
+        let ratio = (width as f64 / self.width as f64).min(height as f64 / self.height as f64);
+        let width = ((self.width as f64 * ratio).round() as u32).max(1);
+        let height = ((self.height as f64 * ratio).round() as u32).max(1);
+
+        let data = if self.is_mono() {
+            let buffer: ImageBuffer<Luma<f32>, Vec<f32>> = ImageBuffer::from_raw(self.width, self.height, self.data.clone()).unwrap();
+            imageops::resize(&buffer, width, height, filter).into_raw()
+        } else {
+            let buffer: ImageBuffer<Rgb<f32>, Vec<f32>> = ImageBuffer::from_raw(self.width, self.height, self.data.clone()).unwrap();
+            imageops::resize(&buffer, width, height, filter).into_raw()
+        };
+
+        Image::from_raw(width, height, self.channels, data)
+    }
+
+    // Converts the image to 8 bits per channel, clipping values outside of the normalized range.
+    pub fn to_dynamic8(&self) -> DynamicImage {
+        let data = self.data
+            .iter()
+            .map(|value| (value.max(0.0).min(1.0) * u8::max_value() as f32).round() as u8)
+            .collect();
+
+        if self.is_mono() {
+            DynamicImage::ImageLuma8(ImageBuffer::from_raw(self.width, self.height, data).unwrap())
+        } else {
+            DynamicImage::ImageRgb8(ImageBuffer::from_raw(self.width, self.height, data).unwrap())
+        }
+    }
+
+    // Converts the image to 16 bits per channel, clipping values outside of the normalized range.
+    pub fn to_dynamic16(&self) -> DynamicImage {
+        let data = self.data
+            .iter()
+            .map(|value| (value.max(0.0).min(1.0) * u16::max_value() as f32).round() as u16)
+            .collect();
+
+        if self.is_mono() {
+            DynamicImage::ImageLuma16(ImageBuffer::from_raw(self.width, self.height, data).unwrap())
+        } else {
+            DynamicImage::ImageRgb16(ImageBuffer::from_raw(self.width, self.height, data).unwrap())
+        }
+    }
+}
@@ -0,0 +1,434 @@
+use super::{
+    Operation,
+    View,
+    Layer,
+    Load,
+    buffer::Image,
+    cfa::Pattern,
+    fits::{
+        self,
+        Card,
+        Depth,
+        Fits,
+    },
+    statistics,
+    error::{
+        self,
+        Error,
+    },
+};
+use std::{
+    fs,
+    path::{
+        Path,
+        PathBuf,
+    },
+    time::UNIX_EPOCH,
+};
+use rayon::prelude::*;
+
+// Dark current roughly doubles with every increase of this many degrees.
+const DARK_DOUBLING_TEMPERATURE: f64 = 6.0;
+
+// A combined calibration frame.
+#[derive(Clone)]
+pub struct Master {
+    pub image: Image,
+    pub pattern: Option<Pattern>,
+    pub exposure: Option<f64>,
+    pub temperature: Option<f64>,
+}
+
+impl Master {
+    // Combines the frames pixel by pixel with kappa-sigma clipping.
+    fn combine(layers: &[Layer], kappa: f32) -> error::Result<Master> {
+        let first = &layers[0].image;
+        if let Some(layer) = layers.iter().find(|layer| {
+            layer.image.width() != first.width()
+                || layer.image.height() != first.height()
+                || layer.image.channels() != first.channels()
+        }) {
+            return Err(Error::input(format!(
+                "Calibration frame \"{}\" does not match the size of the other frames.",
+                layer.metadata.path.as_ref().map(|path| path.display().to_string()).unwrap_or_default()
+            )));
+        }
+
+        let data = (0..first.as_raw().len())
+            .into_par_iter()
+            .map(|i| {
+                let mut values = layers
+                    .iter()
+                    .map(|layer| layer.image.as_raw()[i])
+                    .collect::<Vec<f32>>();
+                statistics::sigma_clipped_mean(&mut values, kappa, kappa)
+            })
+            .collect();
+
+        let average = |values: Vec<f64>| if values.is_empty() {
+            None
+        } else {
+            Some(values.iter().sum::<f64>() / values.len() as f64)
+        };
+
+        Ok(Master {
+            image: Image::from_raw(first.width(), first.height(), first.channels(), data),
+            pattern: layers[0].pattern,
+            exposure: average(layers.iter().filter_map(|layer| layer.metadata.exposure).collect()),
+            temperature: average(layers.iter().filter_map(|layer| layer.metadata.temperature).collect()),
+        })
+    }
+
+    fn read(path: &Path, key: &str) -> Option<Master> {
+        let fits = Fits::read(path).ok()?;
+        if fits.header.get("SRCKEY") != Some(key) {
+            return None;
+        }
+
+        Some(Master {
+            image: fits.to_image_unscaled(),
+            pattern: fits.pattern(),
+            exposure: fits.header.get_f64("EXPTIME"),
+            temperature: fits.header.get_f64("CCD-TEMP"),
+        })
+    }
+
+    fn write(&self, path: &Path, key: &str) -> error::Result<()> {
+        let image = &self.image;
+        let (width, height) = (image.width() as usize, image.height() as usize);
+        let area = width * height;
+
+        let mut data = vec![0.0; area * image.channels()];
+        for (x, y, pixel) in image.enumerate_pixels() {
+            for (c, value) in pixel.iter().enumerate() {
+                data[c * area + y as usize * width + x as usize] = *value;
+            }
+        }
+
+        let mut cards = vec![Card::String("SRCKEY", key.to_string())];
+        if let Some(pattern) = self.pattern {
+            // The rows are written from the bottom up, which shifts the pattern.
+            cards.push(Card::String("BAYERPAT", pattern.shift(0, height as u32 - 1).to_string()));
+            cards.push(Card::String("ROWORDER", "BOTTOM-UP".to_string()));
+        }
+        if let Some(exposure) = self.exposure {
+            cards.push(Card::Float("EXPTIME", exposure));
+        }
+        if let Some(temperature) = self.temperature {
+            cards.push(Card::Float("CCD-TEMP", temperature));
+        }
+
+        fits::write(path, width, height, image.channels(), &data, Depth::Float, &cards)
+    }
+
+    // Subtracts another master pixel by pixel.
+    fn subtract(&self, other: &Master) -> Image {
+        let data = self.image.as_raw()
+            .par_iter()
+            .zip(other.image.as_raw().par_iter())
+            .map(|(a, b)| a - b)
+            .collect();
+
+        Image::from_raw(self.image.width(), self.image.height(), self.image.channels(), data)
+    }
+}
+
+// Identifies a set of calibration frames by their paths, sizes and modification times and the
+// settings they are combined with, hashed with FNV-1a.
+fn source_key(paths: &[PathBuf], settings: &str) -> String {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    let mut feed = |bytes: &[u8]| {
+        for byte in bytes {
+            hash ^= *byte as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+    };
+
+    for path in paths {
+        feed(path.to_string_lossy().as_bytes());
+        if let Ok(metadata) = fs::metadata(path) {
+            feed(&metadata.len().to_le_bytes());
+            let modified = metadata.modified()
+                .ok()
+                .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+                .map(|duration| duration.as_nanos())
+                .unwrap_or(0);
+            feed(&modified.to_le_bytes());
+        }
+    }
+    feed(settings.as_bytes());
+
+    format!("{:016x}", hash)
+}
+
+fn frame_paths(directory: &Path) -> error::Result<Vec<PathBuf>> {
+    let mut paths = fs::read_dir(directory)
+        .map_err(|error| Error::from(error).file(directory))?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.is_file())
+        .collect::<Vec<PathBuf>>();
+    paths.sort();
+
+    Ok(paths)
+}
+
+// Normalizes an image by its median, separately for each color of a mosaic or each channel.
+fn normalize(image: &Image, pattern: Option<Pattern>) -> Image {
+    let channels = image.channels();
+    let group = |x: u32, y: u32, c: usize| match pattern {
+        Some(pattern) => pattern.color_at(x, y).channel(),
+        None => c,
+    };
+
+    let mut medians = [0.0; 3];
+    for (g, median) in medians.iter_mut().enumerate() {
+        let mut values = image
+            .enumerate_pixels()
+            .flat_map(|(x, y, pixel)| pixel
+                .iter()
+                .enumerate()
+                .filter(move |(c, _)| group(x, y, *c) == g)
+                .map(|(_, value)| *value)
+            )
+            .collect::<Vec<f32>>();
+        *median = statistics::median(&mut values);
+    }
+
+    let mut result = image.clone();
+    let width = image.width() as usize;
+    for (i, value) in result.as_raw_mut().iter_mut().enumerate() {
+        let (p, c) = (i / channels, i % channels);
+        let median = medians[group((p % width) as u32, (p / width) as u32, c)];
+        *value = if median > 0.0 { *value / median } else { 1.0 };
+    }
+    result
+}
+
+pub struct Calibration {
+    bias: Option<PathBuf>,
+    dark: Option<PathBuf>,
+    flat: Option<PathBuf>,
+    dark_flat: Option<PathBuf>,
+    cache: Option<PathBuf>,
+    scale_dark: bool,
+    kappa: f32,
+    // Floating point FITS frames are divided by this factor, like the light frames.
+    fits_scale: f64,
+    // Frames are shrunk like the light frames when previewing.
+    preview: bool,
+}
+
+impl Calibration {
+    pub fn new() -> Calibration {
+        Calibration {
+            bias: None,
+            dark: None,
+            flat: None,
+            dark_flat: None,
+            cache: None,
+            scale_dark: false,
+            kappa: 3.0,
+            fits_scale: 1.0,
+            preview: false,
+        }
+    }
+
+    // Sets the directories containing the calibration frames of each kind.
+    pub fn bias(mut self, path: Option<PathBuf>) -> Calibration {
+        self.bias = path;
+        self
+    }
+
+    pub fn dark(mut self, path: Option<PathBuf>) -> Calibration {
+        self.dark = path;
+        self
+    }
+
+    pub fn flat(mut self, path: Option<PathBuf>) -> Calibration {
+        self.flat = path;
+        self
+    }
+
+    pub fn dark_flat(mut self, path: Option<PathBuf>) -> Calibration {
+        self.dark_flat = path;
+        self
+    }
+
+    // Sets the directory in which master frames are stored for reuse.
+    pub fn cache(mut self, path: Option<PathBuf>) -> Calibration {
+        self.cache = path;
+        self
+    }
+
+    // Scales the thermal signal of the master dark to the exposure time and temperature of each frame.
+    pub fn scale_dark(mut self, scale_dark: bool) -> Calibration {
+        self.scale_dark = scale_dark;
+        self
+    }
+
+    // Sets the rejection threshold in standard deviations used when combining frames.
+    pub fn kappa(mut self, kappa: f32) -> Calibration {
+        self.kappa = kappa;
+        self
+    }
+
+    pub fn fits_scale(mut self, factor: f64) -> Calibration {
+        self.fits_scale = factor;
+        self
+    }
+
+    // Loads the calibration frames shrunk like the light frames of a preview.
+    pub fn preview(mut self, preview: bool) -> Calibration {
+        self.preview = preview;
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bias.is_none() && self.dark.is_none() && self.flat.is_none()
+    }
+
+    // Identifies the master combined from the frames in the directory, including the settings and
+    // the key of the master that is subtracted from the frames before combining them, if any.
+    fn key(&self, directory: &Path, dependency: &str) -> error::Result<String> {
+        Ok(source_key(&frame_paths(directory)?, &format!("kappa {} scale {} preview {} {}", self.kappa, self.fits_scale, self.preview, dependency)))
+    }
+
+    // Identifies the master that is subtracted from the flat frames before combining them.
+    fn flat_dependency(&self) -> error::Result<String> {
+        match self.dark_flat.as_ref().or_else(|| self.bias.as_ref()) {
+            Some(directory) => self.key(directory, ""),
+            None => Ok(String::new())
+        }
+    }
+
+    // Loads the master of the given kind from the cache or combines it from its frames.
+    fn master(&self, name: &str, directory: &Option<PathBuf>, dependency: &str, prepare: impl Fn(&mut Layer) -> error::Result<()> + Sync) -> error::Result<Option<Master>> {
+        let directory = match directory {
+            Some(directory) => directory,
+            None => return Ok(None)
+        };
+
+        let key = self.key(directory, dependency)?;
+        let cache = self.cache
+            .as_ref()
+            .map(|cache| cache.join(format!("{}.fits", name)));
+
+        if let Some(path) = &cache {
+            if let Some(master) = Master::read(path, &key) {
+                println!("Using cached master {} \"{}\".", name, path.display());
+                return Ok(Some(master));
+            }
+        }
+
+        let mut layers = Load::new(directory.clone())
+            .fits_scale(self.fits_scale)
+            .preview(self.preview)
+            .apply(View::default())?
+            .layers;
+        println!("Combining {} {} frames ...", layers.len(), name);
+        layers.par_iter_mut().map(|layer| prepare(layer)).collect::<error::Result<()>>()?;
+        let master = Master::combine(&layers, self.kappa)?;
+
+        if let Some(path) = &cache {
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            master.write(path, &key)?;
+        }
+
+        Ok(Some(master))
+    }
+}
+
+impl Operation for Calibration {
+    // The masters are described by their keys, so that changing their frames changes the settings.
+    fn settings(&self) -> String {
+        let key = |directory: &Option<PathBuf>, dependency: &str| directory
+            .as_ref()
+            .map(|directory| self.key(directory, dependency).unwrap_or_else(|_| directory.display().to_string()));
+        format!(
+            "bias {:?} dark {:?} flat {:?} dark-flat {:?} scale-dark {}",
+            key(&self.bias, ""), key(&self.dark, ""), key(&self.flat, &self.flat_dependency().unwrap_or_default()),
+            key(&self.dark_flat, ""), self.scale_dark
+        )
+    }
+
+    fn apply(&self, mut view: View) -> error::Result<View> {
+        println!("Calibrating images ...");
+
+        if self.scale_dark && self.dark.is_some() && self.bias.is_none() {
+            return Err(Error::input("Scaling the dark requires a bias to separate its thermal signal.".to_string()));
+        }
+
+        let bias = self.master("bias", &self.bias, "", |_| Ok(()))?;
+        let dark = self.master("dark", &self.dark, "", |_| Ok(()))?;
+        let dark_flat = self.master("dark-flat", &self.dark_flat, "", |_| Ok(()))?;
+
+        // Flats are calibrated with the dark flat or bias and scaled to a common level before combining.
+        let flat_offset = dark_flat.as_ref().or_else(|| bias.as_ref());
+        let flat = self.master("flat", &self.flat, &self.flat_dependency()?, |layer| {
+            if let Some(offset) = flat_offset {
+                if offset.image.as_raw().len() != layer.image.as_raw().len() {
+                    return Err(Error::input("Master dark flat or bias does not match the size of the flat frames.".to_string()));
+                }
+                for (value, offset) in layer.image.as_raw_mut().iter_mut().zip(offset.image.as_raw()) {
+                    *value -= offset;
+                }
+            }
+            layer.image = normalize(&layer.image, layer.pattern);
+            Ok(())
+        })?.map(|master| normalize(&master.image, master.pattern));
+
+        // The thermal signal of the dark, without the bias.
+        let thermal = match (&dark, &bias) {
+            (Some(dark), Some(bias)) if self.scale_dark => Some(dark.subtract(bias)),
+            _ => None
+        };
+
+        view.layers
+            .par_iter_mut()
+            .map(|layer| {
+                let size = layer.image.as_raw().len();
+                let check = |image: &Image, name: &str| if image.as_raw().len() == size {
+                    Ok(())
+                } else {
+                    Err(Error::input(format!("Master {} does not match the size of the images.", name)))
+                };
+
+                if let (Some(thermal), Some(bias), Some(dark)) = (&thermal, &bias, &dark) {
+                    check(&thermal, "dark")?;
+                    let mut factor = match (layer.metadata.exposure, dark.exposure) {
+                        (Some(exposure), Some(dark)) if dark > 0.0 => exposure / dark,
+                        _ => 1.0
+                    };
+                    if let (Some(temperature), Some(dark)) = (layer.metadata.temperature, dark.temperature) {
+                        factor *= 2.0_f64.powf((temperature - dark) / DARK_DOUBLING_TEMPERATURE);
+                    }
+                    let raw = layer.image.as_raw_mut();
+                    for (i, value) in raw.iter_mut().enumerate() {
+                        *value -= bias.image.as_raw()[i] + thermal.as_raw()[i] * factor as f32;
+                    }
+                } else if let Some(offset) = dark.as_ref().or_else(|| bias.as_ref()) {
+                    check(&offset.image, "dark")?;
+                    for (value, offset) in layer.image.as_raw_mut().iter_mut().zip(offset.image.as_raw()) {
+                        *value -= offset;
+                    }
+                }
+
+                if let Some(flat) = &flat {
+                    check(flat, "flat")?;
+                    for (value, flat) in layer.image.as_raw_mut().iter_mut().zip(flat.as_raw()) {
+                        // Dead areas of the flat are left uncorrected.
+                        if *flat > 0.01 {
+                            *value /= flat;
+                        }
+                    }
+                }
+
+                Ok(())
+            })
+            .collect::<error::Result<()>>()?;
+
+        Ok(view)
+    }
+}
@@ -0,0 +1,87 @@
+use std::fmt;
+
+// Color of a photosite in a color filter array.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Color {
+    Red,
+    Green,
+    Blue,
+}
+
+impl Color {
+    // The channel of this color in an RGB image.
+    pub fn channel(self) -> usize {
+        match self {
+            Color::Red => 0,
+            Color::Green => 1,
+            Color::Blue => 2,
+        }
+    }
+}
+
+// Layout of a 2x2 Bayer color filter array, named by the colors of the top left square
+// in reading order.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Pattern {
+    Rggb,
+    Bggr,
+    Grbg,
+    Gbrg,
+}
+
+impl Pattern {
+    pub fn parse(name: &str) -> Option<Pattern> {
+        match name.trim().to_uppercase().as_str() {
+            "RGGB" => Some(Pattern::Rggb),
+            "BGGR" => Some(Pattern::Bggr),
+            "GRBG" => Some(Pattern::Grbg),
+            "GBRG" => Some(Pattern::Gbrg),
+            _ => None
+        }
+    }
+
+    // Finds the pattern with the given colors of the top left square.
+    pub fn from_colors(colors: [Color; 4]) -> Option<Pattern> {
+        [Pattern::Rggb, Pattern::Bggr, Pattern::Grbg, Pattern::Gbrg]
+            .iter()
+            .copied()
+            .find(|pattern| pattern.colors() == colors)
+    }
+
+    fn colors(self) -> [Color; 4] {
+        use Color::*;
+
+        match self {
+            Pattern::Rggb => [Red, Green, Green, Blue],
+            Pattern::Bggr => [Blue, Green, Green, Red],
+            Pattern::Grbg => [Green, Red, Blue, Green],
+            Pattern::Gbrg => [Green, Blue, Red, Green],
+        }
+    }
+
+    // Returns the color of the photosite at the given position.
+    pub fn color_at(self, x: u32, y: u32) -> Color {
+        self.colors()[(y as usize % 2) * 2 + x as usize % 2]
+    }
+
+    // Returns the pattern as seen from the given offset, used when cropping or flipping a mosaic.
+    pub fn shift(self, x: u32, y: u32) -> Pattern {
+        Pattern::from_colors([
+            self.color_at(x, y),
+            self.color_at(x + 1, y),
+            self.color_at(x, y + 1),
+            self.color_at(x + 1, y + 1),
+        ]).unwrap()
+    }
+}
+
+impl fmt::Display for Pattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", match self {
+            Pattern::Rggb => "RGGB",
+            Pattern::Bggr => "BGGR",
+            Pattern::Grbg => "GRBG",
+            Pattern::Gbrg => "GBRG",
+        })
+    }
+}
@@ -0,0 +1,280 @@
+use super::{
+    Operation,
+    View,
+    buffer::Image,
+    cfa::Pattern,
+    fits::Fits,
+    statistics,
+    error::{
+        self,
+        Error,
+    },
+};
+use std::{
+    fs,
+    path::PathBuf,
+    collections::HashSet,
+};
+use rayon::prelude::*;
+
+// Stars spread over several pixels even when they are undersampled, while defects are isolated. A
+// pixel is only defective if no more than one of its neighbours, such as a second defect next to it,
+// deviates in the same direction by more than a fraction of its deviation and more than the noise.
+const SPREAD: f32 = 0.05;
+const NOISE: f32 = 3.0;
+
+// Returns the values of the neighbouring pixels of the same color. For mosaics, these are the
+// photosites of the same color in the surrounding 5x5 square, otherwise the surrounding 3x3 square.
+fn neighbours(image: &Image, pattern: Option<Pattern>, x: u32, y: u32, c: usize) -> Vec<f32> {
+    let radius = if pattern.is_some() { 2 } else { 1 };
+    let (width, height) = (image.width() as i32, image.height() as i32);
+
+    let mut values = Vec::with_capacity(24);
+    for dy in -radius..=radius {
+        for dx in -radius..=radius {
+            let (nx, ny) = (x as i32 + dx, y as i32 + dy);
+            if (dx, dy) == (0, 0) || nx < 0 || ny < 0 || nx >= width || ny >= height {
+                continue;
+            }
+            if let Some(pattern) = pattern {
+                if pattern.color_at(nx as u32, ny as u32) != pattern.color_at(x, y) {
+                    continue;
+                }
+            }
+            values.push(image.get_pixel(nx as u32, ny as u32)[c]);
+        }
+    }
+    values
+}
+
+// Finds the pixels that deviate from the median of their neighbours by more than the given
+// number of standard deviations, returned as indices into the raw data.
+fn detect(image: &Image, pattern: Option<Pattern>, hot: f32, cold: f32) -> Vec<usize> {
+    let channels = image.channels();
+    let width = image.width() as usize;
+
+    let deviations = (0..image.as_raw().len())
+        .into_par_iter()
+        .map(|i| {
+            let (x, y, c) = (((i / channels) % width) as u32, ((i / channels) / width) as u32, i % channels);
+            let mut values = neighbours(image, pattern, x, y, c);
+            image.as_raw()[i] - statistics::median(&mut values)
+        })
+        .collect::<Vec<f32>>();
+
+    // The noise is estimated robustly from a subsample of the deviations.
+    let step = (deviations.len() / 100_000).max(1);
+    let sample = deviations
+        .iter()
+        .step_by(step)
+        .cloned()
+        .collect::<Vec<f32>>();
+    let sigma = statistics::mad(&sample, statistics::median(&mut sample.clone())).max(f32::EPSILON);
+
+    deviations
+        .par_iter()
+        .enumerate()
+        .filter(|(_, &deviation)| deviation > hot * sigma || deviation < -cold * sigma)
+        .filter(|&(i, &deviation)| {
+            let (x, y, c) = (((i / channels) % width) as u32, ((i / channels) / width) as u32, i % channels);
+            let median = image.as_raw()[i] - deviation;
+            let limit = (SPREAD * deviation.abs()).max(NOISE * sigma);
+            let elevated = neighbours(image, pattern, x, y, c)
+                .iter()
+                .filter(|&&value| (value - median) * deviation.signum() > limit)
+                .count();
+            elevated <= 1
+        })
+        .map(|(i, _)| i)
+        .collect()
+}
+
+// Finds hot pixels in a master dark, which are brighter than the median by the given number of
+// standard deviations.
+fn detect_dark(image: &Image, sigma: f32) -> Vec<usize> {
+    let mut values = image.as_raw().to_vec();
+    let median = statistics::median(&mut values);
+    let deviation = statistics::mad(image.as_raw(), median).max(f32::EPSILON);
+
+    image.as_raw()
+        .iter()
+        .enumerate()
+        .filter(|(_, &value)| value > median + sigma * deviation)
+        .map(|(i, _)| i)
+        .collect()
+}
+
+// Reads a defect map, a text file with the coordinates "x y" of one defective pixel per line.
+fn read_defects(path: &PathBuf) -> error::Result<Vec<(u32, u32)>> {
+    fs::read_to_string(path)
+        .map_err(|error| Error::from(error).file(path))?
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or("").trim())
+        .filter(|line| !line.is_empty())
+        .map(|line| {
+            let mut coordinates = line.split_whitespace().map(|value| value.parse::<u32>());
+            match (coordinates.next(), coordinates.next()) {
+                (Some(Ok(x)), Some(Ok(y))) => Ok((x, y)),
+                _ => Err(Error::input(format!("Invalid line \"{}\" in defect map.", line)).file(path))
+            }
+        })
+        .collect()
+}
+
+pub struct Cosmetic {
+    hot: f32,
+    cold: f32,
+    dark: Option<PathBuf>,
+    defects: Option<PathBuf>
+}
+
+impl Cosmetic {
+    pub fn new() -> Cosmetic {
+        Cosmetic {
+            hot: 5.0,
+            cold: 5.0,
+            dark: None,
+            defects: None
+        }
+    }
+
+    // Sets the number of standard deviations above and below the neighbours for a pixel to be defective.
+    pub fn sigma(mut self, hot: f32, cold: f32) -> Cosmetic {
+        self.hot = hot;
+        self.cold = cold;
+        self
+    }
+
+    // Additionally marks the hot pixels of a master dark FITS file as defective.
+    pub fn dark(mut self, path: Option<PathBuf>) -> Cosmetic {
+        self.dark = path;
+        self
+    }
+
+    // Additionally marks the pixels listed in a defect map file as defective.
+    pub fn defects(mut self, path: Option<PathBuf>) -> Cosmetic {
+        self.defects = path;
+        self
+    }
+}
+
+impl Operation for Cosmetic {
+    fn settings(&self) -> String {
+        format!("hot {} cold {} dark {:?} defects {:?}", self.hot, self.cold, self.dark, self.defects)
+    }
+
+    fn apply(&self, mut view: View) -> error::Result<View> {
+        println!("Correcting defective pixels ...");
+
+        let dark = match &self.dark {
+            Some(path) => Some(Fits::read(path).map_err(|error| error.file(path))?.to_image()),
+            None => None
+        };
+        let dark_defects = dark
+            .as_ref()
+            .map(|dark| detect_dark(dark, self.hot))
+            .unwrap_or_default();
+        let map_defects = match &self.defects {
+            Some(path) => read_defects(path)?,
+            None => Vec::new()
+        };
+
+        view.layers
+            .par_iter_mut()
+            .for_each(|layer| {
+                let image = &layer.image;
+                let channels = image.channels();
+                let width = image.width() as usize;
+
+                let mut defects = detect(image, layer.pattern, self.hot, self.cold)
+                    .into_iter()
+                    .collect::<HashSet<usize>>();
+                if let Some(dark) = &dark {
+                    if dark.width() == image.width() && dark.height() == image.height() && dark.channels() == channels {
+                        defects.extend(dark_defects.iter().cloned());
+                    }
+                }
+                for &(x, y) in &map_defects {
+                    if x < image.width() && y < image.height() {
+                        let i = (y as usize * width + x as usize) * channels;
+                        defects.extend(i..(i + channels));
+                    }
+                }
+
+                // Defective pixels are replaced by the median of their neighbours of the same color.
+                let replacements = defects
+                    .iter()
+                    .map(|&i| {
+                        let (x, y, c) = (((i / channels) % width) as u32, ((i / channels) / width) as u32, i % channels);
+                        let mut values = neighbours(image, layer.pattern, x, y, c);
+                        (i, statistics::median(&mut values))
+                    })
+                    .collect::<Vec<(usize, f32)>>();
+
+                let raw = layer.image.as_raw_mut();
+                for (i, value) in replacements {
+                    raw[i] = value;
+                }
+
+                println!(
+                    "Corrected {} defective pixels in \"{}\".",
+                    defects.len(),
+                    layer.metadata.path.as_ref().map(|path| path.display().to_string()).unwrap_or_default()
+                );
+            });
+
+        Ok(view)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Background with gaussian noise of the given standard deviation from a fixed seed.
+    fn background(width: u32, height: u32, level: f32, noise: f32) -> Image {
+        let data = statistics::normal((width * height) as usize)
+            .iter()
+            .map(|normal| level + noise * normal)
+            .collect();
+        Image::from_raw(width, height, 1, data)
+    }
+
+    #[test]
+    fn undersampled_stars_are_kept_and_hot_pixels_found() {
+        let (width, height) = (48, 48);
+        let mut image = background(width, height, 0.1, 0.002);
+
+        // A star with a FWHM of 1.2 pixels, hardly wider than a pixel, and a hot pixel beside it.
+        let sigma = 1.2 / 2.355;
+        for y in 0..height {
+            for x in 0..width {
+                let (dx, dy) = (x as f32 - 20.3, y as f32 - 20.4);
+                let i = (y * width + x) as usize;
+                image.as_raw_mut()[i] += 0.5 * (-(dx * dx + dy * dy) / (2.0 * sigma * sigma)).exp();
+            }
+        }
+        let hot = (20 * width + 25) as usize;
+        image.as_raw_mut()[hot] += 0.5;
+
+        let defects = detect(&image, None, 5.0, 5.0);
+        assert!(defects.contains(&hot));
+        assert!(defects.iter().all(|&i| {
+            let (x, y) = ((i % width as usize) as i32, (i / width as usize) as i32);
+            (x - 20).abs() > 3 || (y - 20).abs() > 3
+        }));
+    }
+
+    #[test]
+    fn pairs_of_hot_pixels_are_found() {
+        let (width, height) = (32, 32);
+        let mut image = background(width, height, 0.1, 0.002);
+        let pair = [(10 * width + 10) as usize, (10 * width + 11) as usize];
+        for &i in &pair {
+            image.as_raw_mut()[i] += 0.3;
+        }
+
+        let defects = detect(&image, None, 5.0, 5.0);
+        assert!(pair.iter().all(|i| defects.contains(i)));
+    }
+}
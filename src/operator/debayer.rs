@@ -0,0 +1,388 @@
+use super::{
+    Operation,
+    View,
+    buffer::Image,
+    cfa::{
+        Color,
+        Pattern,
+    },
+    error,
+};
+use rayon::prelude::*;
+
+// Demosaicing algorithms, ordered roughly by quality and cost.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Algorithm {
+    // Combines every 2x2 square into one pixel, halving the resolution.
+    SuperPixel,
+    // Averages the neighbouring photosites of each color.
+    Bilinear,
+    // Variable number of gradients, interpolates along the smoothest directions.
+    Vng,
+    // Adaptive homogeneity-directed, interpolates either horizontally or vertically.
+    Ahd,
+}
+
+impl Algorithm {
+    pub fn parse(name: &str) -> Option<Algorithm> {
+        match name.to_lowercase().as_str() {
+            "superpixel" => Some(Algorithm::SuperPixel),
+            "bilinear" => Some(Algorithm::Bilinear),
+            "vng" => Some(Algorithm::Vng),
+            "ahd" => Some(Algorithm::Ahd),
+            _ => None
+        }
+    }
+}
+
+impl Default for Algorithm {
+    fn default() -> Algorithm {
+        Algorithm::Bilinear
+    }
+}
+
+// A Bayer mosaic with mirrored borders that preserve the pattern.
+struct Mosaic<'a> {
+    image: &'a Image,
+    pattern: Pattern,
+}
+
+impl<'a> Mosaic<'a> {
+    fn width(&self) -> i32 {
+        self.image.width() as i32
+    }
+
+    fn height(&self) -> i32 {
+        self.image.height() as i32
+    }
+
+    fn clamp(value: i32, size: i32) -> u32 {
+        let value = if value < 0 { -value } else { value };
+        let value = if value >= size { 2 * (size - 1) - value } else { value };
+        value.max(0).min(size - 1) as u32
+    }
+
+    fn get(&self, x: i32, y: i32) -> f32 {
+        self.image.get_pixel(Mosaic::clamp(x, self.width()), Mosaic::clamp(y, self.height()))[0]
+    }
+
+    fn color(&self, x: i32, y: i32) -> Color {
+        self.pattern.color_at(Mosaic::clamp(x, self.width()), Mosaic::clamp(y, self.height()))
+    }
+
+    // Averages all photosites of the given color in the 3x3 neighbourhood.
+    fn bilinear(&self, x: i32, y: i32, color: Color) -> f32 {
+        if self.color(x, y) == color {
+            return self.get(x, y);
+        }
+
+        let (sum, count) = (-1..=1)
+            .flat_map(|dy| (-1..=1).map(move |dx| (x + dx, y + dy)))
+            .filter(|&(x, y)| self.color(x, y) == color)
+            .fold((0.0, 0), |(sum, count), (x, y)| (sum + self.get(x, y), count + 1));
+
+        sum / count as f32
+    }
+
+    fn bilinear_pixel(&self, x: i32, y: i32) -> [f32; 3] {
+        [
+            self.bilinear(x, y, Color::Red),
+            self.bilinear(x, y, Color::Green),
+            self.bilinear(x, y, Color::Blue),
+        ]
+    }
+}
+
+const DIRECTIONS: [(i32, i32); 8] = [(0, -1), (1, -1), (1, 0), (1, 1), (0, 1), (-1, 1), (-1, 0), (-1, -1)];
+
+fn vng_pixel(mosaic: &Mosaic, x: i32, y: i32) -> [f32; 3] {
+    let own = mosaic.color(x, y);
+    let center = mosaic.get(x, y);
+
+    // Gradients along each direction, photosites two steps apart share the same color.
+    let gradients = DIRECTIONS
+        .iter()
+        .map(|&(dx, dy)| {
+            (mosaic.get(x + dx, y + dy) - mosaic.get(x - dx, y - dy)).abs()
+                + (mosaic.get(x + 2 * dx, y + 2 * dy) - center).abs()
+                + (mosaic.get(x + dx - dy, y + dy + dx) - mosaic.get(x - dx - dy, y - dy + dx)).abs() / 2.0
+                + (mosaic.get(x + dx + dy, y + dy - dx) - mosaic.get(x - dx + dy, y - dy - dx)).abs() / 2.0
+        })
+        .collect::<Vec<f32>>();
+
+    let min = gradients.iter().cloned().fold(f32::INFINITY, f32::min);
+    let max = gradients.iter().cloned().fold(f32::NEG_INFINITY, f32::max);
+    let threshold = 1.5 * min + 0.5 * (max - min);
+
+    // Averages the color differences of the neighbours in all sufficiently smooth directions.
+    let mut sum = [0.0; 3];
+    let mut count = 0;
+    for (&(dx, dy), &gradient) in DIRECTIONS.iter().zip(gradients.iter()) {
+        if gradient <= threshold {
+            let neighbour = mosaic.bilinear_pixel(x + dx, y + dy);
+            for (c, value) in sum.iter_mut().enumerate() {
+                *value += neighbour[c] - neighbour[own.channel()];
+            }
+            count += 1;
+        }
+    }
+
+    let mut pixel = [0.0; 3];
+    for (c, value) in pixel.iter_mut().enumerate() {
+        *value = if c == own.channel() {
+            center
+        } else {
+            center + sum[c] / count as f32
+        };
+    }
+    pixel
+}
+
+// Interpolates the green channel along one direction using the second derivative
+// of the center color as correction.
+fn directional_green(mosaic: &Mosaic, x: i32, y: i32, (dx, dy): (i32, i32)) -> f32 {
+    if mosaic.color(x, y) == Color::Green {
+        return mosaic.get(x, y);
+    }
+
+    (mosaic.get(x - dx, y - dy) + mosaic.get(x + dx, y + dy)) / 2.0
+        + (2.0 * mosaic.get(x, y) - mosaic.get(x - 2 * dx, y - 2 * dy) - mosaic.get(x + 2 * dx, y + 2 * dy)) / 4.0
+}
+
+// Builds a full RGB image interpolated along one direction.
+fn directional_image(mosaic: &Mosaic, direction: (i32, i32)) -> Vec<[f32; 3]> {
+    let (width, height) = (mosaic.width(), mosaic.height());
+    let green = (0..height)
+        .into_par_iter()
+        .flat_map(|y| (0..width).into_par_iter().map(move |x| directional_green(mosaic, x, y, direction)))
+        .collect::<Vec<f32>>();
+    let green_at = |x: i32, y: i32| green[(Mosaic::clamp(y, height) as i32 * width + Mosaic::clamp(x, width) as i32) as usize];
+
+    (0..height)
+        .into_par_iter()
+        .flat_map(|y| (0..width).into_par_iter().map(move |x| (x, y)))
+        .map(|(x, y)| {
+            let g = green_at(x, y);
+            let mut pixel = [0.0, g, 0.0];
+            for &color in &[Color::Red, Color::Blue] {
+                pixel[color.channel()] = if mosaic.color(x, y) == color {
+                    mosaic.get(x, y)
+                } else {
+                    // Interpolates the color difference to the green channel.
+                    let (sum, count) = (-1..=1)
+                        .flat_map(|dy| (-1..=1).map(move |dx| (x + dx, y + dy)))
+                        .filter(|&(x, y)| mosaic.color(x, y) == color)
+                        .fold((0.0, 0), |(sum, count), (x, y)| (sum + mosaic.get(x, y) - green_at(x, y), count + 1));
+                    g + sum / count as f32
+                };
+            }
+            pixel
+        })
+        .collect()
+}
+
+fn ahd(mosaic: &Mosaic) -> Vec<f32> {
+    let (width, height) = (mosaic.width(), mosaic.height());
+    let images = [directional_image(mosaic, (1, 0)), directional_image(mosaic, (0, 1))];
+    let at = |image: &[[f32; 3]], x: i32, y: i32| image[(Mosaic::clamp(y, height) as i32 * width + Mosaic::clamp(x, width) as i32) as usize];
+
+    // Luminance and chrominance differences to a neighbour.
+    let difference = |a: [f32; 3], b: [f32; 3]| {
+        let luminance = ((a[0] + 2.0 * a[1] + a[2]) - (b[0] + 2.0 * b[1] + b[2])).abs() / 4.0;
+        let chrominance = ((a[0] - a[1]) - (b[0] - b[1])).abs() + ((a[2] - a[1]) - (b[2] - b[1])).abs();
+        (luminance, chrominance)
+    };
+
+    // Counts the neighbours that are similar to the center pixel in each directional image.
+    let homogeneity = (0..height)
+        .into_par_iter()
+        .flat_map(|y| (0..width).into_par_iter().map(move |x| (x, y)))
+        .map(|(x, y)| {
+            let (h, v) = (&images[0], &images[1]);
+            let (lh1, ch1) = difference(at(h, x, y), at(h, x - 1, y));
+            let (lh2, ch2) = difference(at(h, x, y), at(h, x + 1, y));
+            let (lv1, cv1) = difference(at(v, x, y), at(v, x, y - 1));
+            let (lv2, cv2) = difference(at(v, x, y), at(v, x, y + 1));
+            let luminance = lh1.max(lh2).min(lv1.max(lv2));
+            let chrominance = ch1.max(ch2).min(cv1.max(cv2));
+
+            let mut counts = [0u8; 2];
+            for (count, image) in counts.iter_mut().zip(images.iter()) {
+                for &(dx, dy) in &[(-1, 0), (1, 0), (0, -1), (0, 1)] {
+                    let (l, c) = difference(at(image, x, y), at(image, x + dx, y + dy));
+                    if l <= luminance && c <= chrominance {
+                        *count += 1;
+                    }
+                }
+            }
+            counts
+        })
+        .collect::<Vec<[u8; 2]>>();
+
+    (0..height)
+        .into_par_iter()
+        .flat_map(|y| (0..width).into_par_iter().map(move |x| (x, y)))
+        .flat_map(|(x, y)| {
+            // Sums up the homogeneity in the 3x3 neighbourhood to pick a direction.
+            let mut scores = [0u32; 2];
+            for dy in -1..=1 {
+                for dx in -1..=1 {
+                    let i = (Mosaic::clamp(y + dy, height) as i32 * width + Mosaic::clamp(x + dx, width) as i32) as usize;
+                    scores[0] += homogeneity[i][0] as u32;
+                    scores[1] += homogeneity[i][1] as u32;
+                }
+            }
+
+            let (h, v) = (at(&images[0], x, y), at(&images[1], x, y));
+            let pixel = if scores[0] > scores[1] {
+                h
+            } else if scores[1] > scores[0] {
+                v
+            } else {
+                [(h[0] + v[0]) / 2.0, (h[1] + v[1]) / 2.0, (h[2] + v[2]) / 2.0]
+            };
+            pixel.to_vec()
+        })
+        .collect()
+}
+
+// Combines every 2x2 square of the mosaic into one pixel. The last row or column of a mosaic of
+// odd size has no complete squares and is left out.
+fn super_pixel(mosaic: &Mosaic) -> Image {
+    let (width, height) = (mosaic.width() / 2, mosaic.height() / 2);
+
+    let data = (0..height)
+        .into_par_iter()
+        .flat_map(|y| (0..width).into_par_iter().map(move |x| (x, y)))
+        .flat_map(|(x, y)| {
+            let mut pixel = [0.0; 3];
+            let mut counts = [0.0; 3];
+            for (dx, dy) in [(0, 0), (1, 0), (0, 1), (1, 1)].iter() {
+                let (x, y) = (2 * x + dx, 2 * y + dy);
+                let c = mosaic.color(x, y).channel();
+                pixel[c] += mosaic.get(x, y);
+                counts[c] += 1.0;
+            }
+            (0..3).map(move |c| pixel[c] / counts[c]).collect::<Vec<f32>>()
+        })
+        .collect();
+
+    Image::from_raw(width as u32, height as u32, 3, data)
+}
+
+// Converts the mosaic of a layer into an RGB image.
+pub fn demosaic(image: &Image, pattern: Pattern, algorithm: Algorithm) -> Image {
+    let mosaic = Mosaic {
+        image,
+        pattern,
+    };
+    let (width, height) = (mosaic.width(), mosaic.height());
+
+    let pixels = |f: fn(&Mosaic, i32, i32) -> [f32; 3]| (0..height)
+        .into_par_iter()
+        .flat_map(|y| (0..width).into_par_iter().map(move |x| (x, y)))
+        .flat_map(|(x, y)| f(&mosaic, x, y).to_vec())
+        .collect::<Vec<f32>>();
+
+    match algorithm {
+        Algorithm::SuperPixel => super_pixel(&mosaic),
+        Algorithm::Bilinear => Image::from_raw(width as u32, height as u32, 3, pixels(|mosaic, x, y| mosaic.bilinear_pixel(x, y))),
+        Algorithm::Vng => Image::from_raw(width as u32, height as u32, 3, pixels(vng_pixel)),
+        Algorithm::Ahd => Image::from_raw(width as u32, height as u32, 3, ahd(&mosaic)),
+    }
+}
+
+pub struct Debayer {
+    algorithm: Algorithm,
+    pattern: Option<Pattern>
+}
+
+impl Debayer {
+    pub fn new(algorithm: Algorithm) -> Debayer {
+        Debayer {
+            algorithm,
+            pattern: None
+        }
+    }
+
+    // Overrides the pattern of the layers, this also debayers mono layers without a known pattern.
+    pub fn pattern(mut self, pattern: Option<Pattern>) -> Debayer {
+        self.pattern = pattern;
+        self
+    }
+}
+
+impl Operation for Debayer {
+    fn settings(&self) -> String {
+        format!("{:?} {:?}", self.algorithm, self.pattern)
+    }
+
+    fn apply(&self, mut view: View) -> error::Result<View> {
+        println!("Debayering images ...");
+
+        view.layers
+            .par_iter_mut()
+            .filter(|layer| layer.image.is_mono())
+            .for_each(|layer| {
+                if let Some(pattern) = self.pattern.or(layer.pattern) {
+                    layer.image = demosaic(&layer.image, pattern, self.algorithm);
+                    layer.pattern = None;
+                }
+            });
+
+        Ok(view)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALGORITHMS: [Algorithm; 4] = [Algorithm::SuperPixel, Algorithm::Bilinear, Algorithm::Vng, Algorithm::Ahd];
+    const PATTERNS: [Pattern; 4] = [Pattern::Rggb, Pattern::Bggr, Pattern::Grbg, Pattern::Gbrg];
+
+    // The mosaic a camera with the given pattern records of a scene of one color.
+    fn mosaic(width: u32, height: u32, pattern: Pattern, color: [f32; 3]) -> Image {
+        let data = (0..(width * height))
+            .map(|i| color[pattern.color_at(i % width, i / width).channel()])
+            .collect();
+        Image::from_raw(width, height, 1, data)
+    }
+
+    fn assert_color(image: &Image, color: [f32; 3], algorithm: Algorithm, pattern: Pattern) {
+        for value in image.as_raw().chunks(3) {
+            for c in 0..3 {
+                assert!((value[c] - color[c]).abs() < 1e-5, "{:?} with {} gives {:?} instead of {:?}", algorithm, pattern, value, color);
+            }
+        }
+    }
+
+    #[test]
+    fn gray_stays_gray() {
+        for &algorithm in &ALGORITHMS {
+            for &pattern in &PATTERNS {
+                let image = demosaic(&mosaic(12, 10, pattern, [0.4; 3]), pattern, algorithm);
+                assert_color(&image, [0.4; 3], algorithm, pattern);
+            }
+        }
+    }
+
+    #[test]
+    fn primary_colors_are_reconstructed() {
+        for &algorithm in &ALGORITHMS {
+            for &pattern in &PATTERNS {
+                for &color in &[[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]] {
+                    let image = demosaic(&mosaic(12, 10, pattern, color), pattern, algorithm);
+                    assert_color(&image, color, algorithm, pattern);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn super_pixels_leave_out_odd_rows_and_columns() {
+        let image = demosaic(&mosaic(7, 5, Pattern::Rggb, [0.2, 0.5, 0.8]), Pattern::Rggb, Algorithm::SuperPixel);
+        assert_eq!((image.width(), image.height()), (3, 2));
+        assert_color(&image, [0.2, 0.5, 0.8], Algorithm::SuperPixel, Pattern::Rggb);
+    }
+}
@@ -0,0 +1,240 @@
+use super::{
+    Layer,
+    buffer::Image,
+    strategy::{
+        Strategy,
+        Sample,
+        Weighted,
+    },
+    transform::{
+        Point,
+        Transform,
+    },
+};
+use rayon::prelude::*;
+
+// Rows of the canvas that the drops are added to together.
+const BAND: usize = 16;
+
+// Drops the pixels of dithered frames onto a finer grid as squares smaller than the pixels, which
+// recovers resolution that a single undersampled frame does not have. Each pixel of the joined image
+// is the average of the drops covering it, weighted by the area they cover. The weight map tells how
+// much data went into each pixel.
+pub struct Drizzle {
+    scale: f32,
+    pixfrac: f32,
+}
+
+impl Drizzle {
+    pub fn new() -> Drizzle {
+        Drizzle {
+            scale: 2.0,
+            pixfrac: 0.7,
+        }
+    }
+
+    // Sets the resolution of the joined image relative to the reference.
+    pub fn scale(mut self, scale: f32) -> Drizzle {
+        self.scale = scale;
+        self
+    }
+
+    // Sets the side of the drops as a fraction of the side of a pixel. Smaller drops give sharper
+    // results, but need more frames to cover every pixel of the joined image.
+    pub fn pixfrac(mut self, pixfrac: f32) -> Drizzle {
+        self.pixfrac = pixfrac;
+        self
+    }
+
+    // Adds the drops of all pixels of a layer to the weighted sums of the values and to the total
+    // weights of the pixels of the canvas.
+    fn drop(&self, layer: &Layer, weight: f32, canvas: &Transform, size: (u32, u32), sums: &mut [f32], totals: &mut [f32]) {
+        let transform = layer.transform.then(canvas);
+        let image = &layer.image;
+        let (columns, source) = (image.width() as usize, image.channels());
+        let channels = sums.len() / totals.len();
+        let (width, height) = (size.0 as usize, size.1 as usize);
+        let half = self.pixfrac / 2.0;
+
+        // The squares of the pixels on the canvas, as left, right, top and bottom edge.
+        let drops = (0..(columns * image.height() as usize))
+            .into_par_iter()
+            .filter_map(|i| {
+                // Pixels without data, such as rejected defects, are left out.
+                if image.as_raw()[(i * source)..((i + 1) * source)].iter().any(|value| !value.is_finite()) {
+                    return None;
+                }
+
+                // The drop is projected as a square of the same center and area, which is exact for
+                // translations and close enough for the small rotations and distortions between frames.
+                let (x, y) = ((i % columns) as f32, (i / columns) as f32);
+                let corners = [(-half, -half), (half, -half), (half, half), (-half, half)]
+                    .iter()
+                    .map(|(dx, dy)| transform.apply(&Point::new(x + dx, y + dy)))
+                    .collect::<Vec<Point>>();
+                let area = (0..4)
+                    .map(|i| {
+                        let (a, b) = (corners[i], corners[(i + 1) % 4]);
+                        a.x * b.y - b.x * a.y
+                    })
+                    .sum::<f32>()
+                    .abs() / 2.0;
+                let center = transform.apply(&Point::new(x, y));
+                let half = area.sqrt() / 2.0;
+                Some((i, [center.x - half, center.x + half, center.y - half, center.y + half]))
+            })
+            .collect::<Vec<(usize, [f32; 4])>>();
+
+        // Pixels of the canvas are centered on integer coordinates.
+        let row = |edge: f32| ((edge + 0.5).floor().max(0.0) as usize).min(height - 1);
+
+        // The drops are sorted into the bands of rows they touch, so that the bands are filled in
+        // parallel.
+        let mut bands = vec![Vec::new(); (height + BAND - 1) / BAND];
+        for (k, (_, [_, _, top, bottom])) in drops.iter().enumerate() {
+            if *bottom + 0.5 >= 0.0 && *top - 0.5 < height as f32 {
+                for band in &mut bands[(row(*top) / BAND)..=(row(*bottom) / BAND)] {
+                    band.push(k);
+                }
+            }
+        }
+
+        sums.par_chunks_mut(BAND * width * channels)
+            .zip(totals.par_chunks_mut(BAND * width))
+            .zip(bands)
+            .enumerate()
+            .for_each(|(band, ((sums, totals), indices))| {
+                let rows = (band * BAND)..((band + 1) * BAND).min(height);
+                for (i, [left, right, top, bottom]) in indices.into_iter().map(|k| drops[k]) {
+                    let pixel = &image.as_raw()[(i * source)..((i + 1) * source)];
+                    let columns = ((left + 0.5).floor() as i64).max(0)..=((right + 0.5).floor() as i64).min(width as i64 - 1);
+                    for v in row(top).max(rows.start)..=row(bottom).min(rows.end - 1) {
+                        let overlap_y = bottom.min(v as f32 + 0.5) - top.max(v as f32 - 0.5);
+                        if overlap_y <= 0.0 {
+                            continue;
+                        }
+                        for u in columns.clone() {
+                            let overlap_x = right.min(u as f32 + 0.5) - left.max(u as f32 - 0.5);
+                            if overlap_x <= 0.0 {
+                                continue;
+                            }
+
+                            let i = (v - rows.start) * width + u as usize;
+                            let weight = weight * overlap_x * overlap_y;
+                            totals[i] += weight;
+                            for c in 0..channels {
+                                // Mono layers contribute equally to all channels.
+                                sums[i * channels + c] += pixel[c.min(source - 1)] * weight;
+                            }
+                        }
+                    }
+                }
+            });
+    }
+}
+
+impl Strategy for Drizzle {
+    fn name(&self) -> String {
+        format!("drizzle at {}x scale with drops of {} pixels", self.scale, self.pixfrac)
+    }
+
+    fn scale(&self) -> f32 {
+        self.scale
+    }
+
+    // Single pixels are averaged by their weights.
+    fn combine(&self, samples: &mut Vec<Sample>) -> f32 {
+        Weighted.combine(samples)
+    }
+
+    fn scatter(&self, layers: &[Layer], weights: &[f32], canvas: &Transform, size: (u32, u32), channels: usize) -> Option<(Image, Image)> {
+        let area = size.0 as usize * size.1 as usize;
+
+        // Layers are dropped one after another onto the same canvas, each one in parallel.
+        let mut sums = vec![0.0; area * channels];
+        let mut totals = vec![0.0; area];
+        for (layer, &weight) in layers.iter().zip(weights) {
+            self.drop(layer, weight, canvas, size, &mut sums, &mut totals);
+        }
+
+        // Pixels that no drop covers stay black.
+        let data = sums
+            .par_iter()
+            .enumerate()
+            .map(|(i, sum)| {
+                let total = totals[i / channels];
+                if total > 0.0 { sum / total } else { 0.0 }
+            })
+            .collect();
+
+        Some((
+            Image::from_raw(size.0, size.1, channels, data),
+            Image::from_raw(size.0, size.1, 1, totals),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::{
+        Operation,
+        View,
+        join::Join,
+    };
+
+    // Values that differ at every pixel.
+    fn layer(width: u32, height: u32) -> Layer {
+        let data = (0..(width * height)).map(|i| ((i * 37) % 17) as f32 / 17.0).collect();
+        Layer::new(Image::from_raw(width, height, 1, data))
+    }
+
+    #[test]
+    fn full_drops_in_place_keep_the_layer() {
+        let layer = layer(20, 16);
+        let (image, weights) = Drizzle::new()
+            .scale(1.0)
+            .pixfrac(1.0)
+            .scatter(std::slice::from_ref(&layer), &[1.0], &Transform::identity(), (20, 16), 1)
+            .unwrap();
+
+        for (value, expected) in image.as_raw().iter().zip(layer.image.as_raw()) {
+            assert!((value - expected).abs() < 1e-5);
+        }
+        assert!(weights.as_raw().iter().all(|&weight| (weight - 1.0).abs() < 1e-5));
+    }
+
+    #[test]
+    fn weights_stay_within_the_footprint() {
+        let mut layer = layer(20, 16);
+        layer.transform = Transform::translation(10.25, 6.5);
+        let (min, max) = layer.transform.bounds(20, 16);
+        let (_, weights) = Drizzle::new()
+            .scale(1.0)
+            .pixfrac(0.7)
+            .scatter(&[layer], &[1.0], &Transform::identity(), (40, 30), 1)
+            .unwrap();
+
+        for (i, &weight) in weights.as_raw().iter().enumerate() {
+            let (x, y) = ((i % 40) as f32, (i / 40) as f32);
+            if x + 0.5 <= min.x || x - 0.5 >= max.x || y + 0.5 <= min.y || y - 0.5 >= max.y {
+                assert_eq!(weight, 0.0, "weight at {} {}", x, y);
+            }
+        }
+        // The drops cover the squared fraction of the area of their pixels.
+        let total = weights.as_raw().iter().sum::<f32>();
+        assert!((total - 20.0 * 16.0 * 0.49).abs() < 1e-2, "total weight {}", total);
+    }
+
+    #[test]
+    fn finer_canvases_are_scaled_exactly() {
+        let view = View {
+            layers: vec![layer(20, 16)],
+            reference: Some(0),
+            ..View::default()
+        };
+        let view = Join::new().strategy(Drizzle::new().scale(2.0)).apply(view).unwrap();
+        let image = &view.layers[0].image;
+        assert_eq!((image.width(), image.height()), (40, 32));
+    }
+}
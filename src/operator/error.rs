@@ -1,20 +1,49 @@
 use std::{
     error,
-    fmt
+    fmt,
+    io,
+    path::Path,
 };
 use image::ImageError;
 
+// Describes the category of an error, mainly used to map errors to exit codes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorKind {
+    Io,
+    Image,
+    Input,
+}
+
 #[derive(Clone, Debug)]
 pub struct Error {
+    kind: ErrorKind,
     message: String
 }
 
 impl Error {
-    fn new(message: String) -> Error {
+    fn new(kind: ErrorKind, message: String) -> Error {
         Error {
+            kind,
             message
         }
     }
+
+    pub fn input(message: String) -> Error {
+        Error::new(ErrorKind::Input, message)
+    }
+
+    pub fn image(message: String) -> Error {
+        Error::new(ErrorKind::Image, message)
+    }
+
+    // Prefixes the message with the path of the file that caused the error.
+    pub fn file(self, path: &Path) -> Error {
+        Error::new(self.kind, format!("\"{}\": {}", path.display(), self.message))
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        self.kind
+    }
 }
 
 impl error::Error for Error {}
@@ -27,7 +56,13 @@ impl fmt::Display for Error {
 
 impl From<ImageError> for Error {
     fn from(error: ImageError) -> Error {
-        Error::new(format!("{}", error))
+        Error::new(ErrorKind::Image, format!("{}", error))
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Error {
+        Error::new(ErrorKind::Io, format!("{}", error))
     }
 }
 
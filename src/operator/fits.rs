@@ -0,0 +1,513 @@
+use super::{
+    Metadata,
+    cfa::Pattern,
+    error::{
+        self,
+        Error,
+    },
+};
+use std::{
+    fs,
+    path::Path,
+    collections::HashMap,
+};
+use super::buffer::Image;
+
+const BLOCK_SIZE: usize = 2880;
+const CARD_SIZE: usize = 80;
+// Characters of a string value that fit into a card between the quotes.
+const STRING_SIZE: usize = 68;
+
+// Returns true if the path has an extension commonly used for FITS files.
+pub fn is_fits(path: &Path) -> bool {
+    path.extension()
+        .map(|extension| {
+            let extension = extension.to_string_lossy().to_lowercase();
+            extension == "fits" || extension == "fit" || extension == "fts"
+        })
+        .unwrap_or(false)
+}
+
+// The header of a FITS file, consisting of keyword value pairs.
+#[derive(Clone, Debug, Default)]
+pub struct Header {
+    keywords: HashMap<String, String>,
+}
+
+impl Header {
+    fn parse(bytes: &[u8]) -> Option<(Header, usize)> {
+        let mut keywords = HashMap::new();
+
+        for (i, card) in bytes.chunks(CARD_SIZE).enumerate() {
+            if card.len() < CARD_SIZE {
+                return None;
+            }
+            // Only the value is converted, so that bytes outside of ASCII cannot split the card.
+            let keyword = String::from_utf8_lossy(&card[..8]).trim().to_string();
+
+            if keyword == "END" {
+                // The header is padded to a multiple of the block size.
+                let length = ((i + 1) * CARD_SIZE + BLOCK_SIZE - 1) / BLOCK_SIZE * BLOCK_SIZE;
+                return Some((Header { keywords }, length));
+            }
+
+            if &card[8..10] == b"= " {
+                keywords.insert(keyword, parse_value(&String::from_utf8_lossy(&card[10..])));
+            }
+        }
+
+        None
+    }
+
+    pub fn get(&self, keyword: &str) -> Option<&str> {
+        self.keywords.get(keyword).map(|value| value.as_str())
+    }
+
+    pub fn get_f64(&self, keyword: &str) -> Option<f64> {
+        self.get(keyword).and_then(|value| value.parse().ok())
+    }
+
+    pub fn get_i64(&self, keyword: &str) -> Option<i64> {
+        self.get(keyword).and_then(|value| value.parse().ok())
+    }
+
+    pub fn keywords(&self) -> &HashMap<String, String> {
+        &self.keywords
+    }
+}
+
+// Extracts the value of a header card, stripping quotes and comments.
+fn parse_value(value: &str) -> String {
+    let value = value.trim_start();
+
+    if let Some(string) = value.strip_prefix('\'') {
+        // Quotes inside of strings are escaped by doubling them.
+        let mut result = String::new();
+        let mut chars = string.chars().peekable();
+        while let Some(c) = chars.next() {
+            if c == '\'' {
+                if chars.peek() == Some(&'\'') {
+                    chars.next();
+                } else {
+                    break;
+                }
+            }
+            result.push(c);
+        }
+        result.trim_end().to_string()
+    } else {
+        value
+            .split('/')
+            .next()
+            .unwrap_or("")
+            .trim()
+            .to_string()
+    }
+}
+
+// Image data of the primary HDU, scaled to physical values.
+pub struct Fits {
+    pub header: Header,
+    pub width: usize,
+    pub height: usize,
+    pub planes: usize,
+    pub bitpix: i64,
+    pub data: Vec<f64>,
+}
+
+impl Fits {
+    pub fn read(path: &Path) -> error::Result<Fits> {
+        let bytes = fs::read(path)?;
+        Fits::parse(&bytes)
+            .map_err(|message| Error::image(format!("Invalid FITS file, {}.", message)))
+    }
+
+    fn parse(bytes: &[u8]) -> std::result::Result<Fits, String> {
+        if !bytes.starts_with(b"SIMPLE  =") {
+            return Err("missing SIMPLE keyword".to_string());
+        }
+
+        let (header, offset) = Header::parse(bytes)
+            .ok_or_else(|| "missing END keyword".to_string())?;
+
+        let bitpix = header.get_i64("BITPIX")
+            .ok_or_else(|| "missing BITPIX keyword".to_string())?;
+        let axes = header.get_i64("NAXIS").unwrap_or(0);
+        let width = header.get_i64("NAXIS1").unwrap_or(0) as usize;
+        let height = header.get_i64("NAXIS2").unwrap_or(0) as usize;
+        let planes = if axes >= 3 {
+            header.get_i64("NAXIS3").unwrap_or(1) as usize
+        } else {
+            1
+        };
+
+        if axes < 2 || width == 0 || height == 0 {
+            return Err(format!("unsupported number of axes {}", axes));
+        }
+        if planes != 1 && planes != 3 {
+            return Err(format!("unsupported number of planes {}", planes));
+        }
+
+        let bzero = header.get_f64("BZERO").unwrap_or(0.0);
+        let bscale = header.get_f64("BSCALE").unwrap_or(1.0);
+        let count = width * height * planes;
+        let size = (bitpix.abs() / 8) as usize;
+
+        if ![8, 16, 32, -32, -64].contains(&bitpix) {
+            return Err(format!("unsupported BITPIX {}", bitpix));
+        }
+
+        let raw = bytes.get(offset..(offset + count * size))
+            .ok_or_else(|| "data is truncated".to_string())?;
+
+        let data = raw
+            .chunks_exact(size)
+            .map(|value| {
+                let value = match bitpix {
+                    8 => value[0] as f64,
+                    16 => i16::from_be_bytes([value[0], value[1]]) as f64,
+                    32 => i32::from_be_bytes([value[0], value[1], value[2], value[3]]) as f64,
+                    -32 => f32::from_be_bytes([value[0], value[1], value[2], value[3]]) as f64,
+                    -64 => f64::from_be_bytes([
+                        value[0], value[1], value[2], value[3],
+                        value[4], value[5], value[6], value[7]
+                    ]),
+                    _ => unreachable!()
+                };
+                bzero + bscale * value
+            })
+            .collect::<Vec<f64>>();
+
+        Ok(Fits {
+            header,
+            width,
+            height,
+            planes,
+            bitpix,
+            data,
+        })
+    }
+
+    // Returns the range of physical values that is mapped to black and white. The mapping is the
+    // same for all files of a data type, so that calibration and stacking stay linear: integer data
+    // covers the nominal range of its type, floating point data is divided by the given factor.
+    fn range(&self, factor: f64) -> (f64, f64) {
+        let bzero = self.header.get_f64("BZERO").unwrap_or(0.0);
+        let bscale = self.header.get_f64("BSCALE").unwrap_or(1.0);
+        let (min, max) = match self.bitpix {
+            8 => (0.0, u8::max_value() as f64),
+            16 => (i16::min_value() as f64, i16::max_value() as f64),
+            32 => (i32::min_value() as f64, i32::max_value() as f64),
+            _ => return (0.0, factor)
+        };
+
+        // Signed integer data with an offset is used to store unsigned values.
+        let (min, max) = (bzero + bscale * min, bzero + bscale * max);
+        (min.max(0.0).min(max), max)
+    }
+
+    // Converts the data to an image normalized to one. FITS stores the bottom row first,
+    // so the rows are flipped.
+    pub fn to_image(&self) -> Image {
+        self.to_image_divided(1.0)
+    }
+
+    // Converts the data to an image like to_image, dividing floating point data by the given
+    // factor, for data that is not normalized to one such as sensor values in ADU.
+    pub fn to_image_divided(&self, factor: f64) -> Image {
+        let (min, max) = self.range(factor);
+        self.to_image_scaled(min, max)
+    }
+
+    // Converts the data to an image without scaling, for data that is already normalized.
+    pub fn to_image_unscaled(&self) -> Image {
+        self.to_image_scaled(0.0, 1.0)
+    }
+
+    fn to_image_scaled(&self, min: f64, max: f64) -> Image {
+        let area = self.width * self.height;
+
+        let mut image = Image::new(self.width as u32, self.height as u32, self.planes);
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let i = (self.height - 1 - y) * self.width + x;
+                let pixel = image.get_pixel_mut(x as u32, y as u32);
+                for (c, value) in pixel.iter_mut().enumerate() {
+                    *value = ((self.data[c * area + i] - min) / (max - min)) as f32;
+                }
+            }
+        }
+
+        image
+    }
+
+    // Returns the color filter pattern of undemosaiced data as seen after flipping the rows.
+    pub fn pattern(&self) -> Option<Pattern> {
+        if self.planes != 1 {
+            return None;
+        }
+
+        let pattern = Pattern::parse(self.header.get("BAYERPAT")?)?;
+        let x = self.header.get_i64("XBAYROFF").unwrap_or(0).rem_euclid(2) as u32;
+        let y = self.header.get_i64("YBAYROFF").unwrap_or(0).rem_euclid(2) as u32;
+        let pattern = pattern.shift(x, y);
+
+        // Unless stated otherwise, the pattern refers to the rows from the top down.
+        if self.header.get("ROWORDER") == Some("BOTTOM-UP") {
+            Some(pattern.shift(0, self.height as u32 - 1))
+        } else {
+            Some(pattern)
+        }
+    }
+
+    pub fn metadata(&self) -> Metadata {
+        let header = &self.header;
+
+        Metadata {
+            exposure: header.get_f64("EXPTIME").or_else(|| header.get_f64("EXPOSURE")),
+            gain: header.get_f64("GAIN"),
+            temperature: header.get_f64("CCD-TEMP"),
+            date: header.get("DATE-OBS").map(String::from),
+            filter: header.get("FILTER").map(String::from),
+            keywords: header.keywords().clone(),
+            ..
+            Metadata::default()
+        }
+    }
+}
+
+// The data type used to store pixel values when writing FITS files.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Depth {
+    // Unsigned 16-bit integers, stored with an offset of 32768.
+    Integer,
+    // 32-bit floating point numbers normalized to one.
+    Float,
+}
+
+impl Default for Depth {
+    fn default() -> Depth {
+        Depth::Float
+    }
+}
+
+// A header card that is written to a FITS file.
+pub enum Card {
+    Logical(&'static str, bool),
+    Integer(&'static str, i64),
+    Float(&'static str, f64),
+    String(&'static str, String),
+    History(String),
+}
+
+impl Card {
+    fn format(&self) -> String {
+        let card = match self {
+            Card::Logical(keyword, value) => format!("{:<8}= {:>20}", keyword, if *value { "T" } else { "F" }),
+            Card::Integer(keyword, value) => format!("{:<8}= {:>20}", keyword, value),
+            // Infinity and NaN cannot be written, their value is left undefined.
+            Card::Float(keyword, value) if !value.is_finite() => format!("{:<8}= ", keyword),
+            Card::Float(keyword, value) => format!("{:<8}= {:>20}", keyword, format_float(*value)),
+            Card::String(keyword, value) => {
+                // Long values are cut to fit between the quotes, without splitting an escaped quote.
+                let mut escaped = String::new();
+                for c in value.chars().filter(|c| c.is_ascii() && !c.is_ascii_control()) {
+                    let piece = if c == '\'' { "''".to_string() } else { c.to_string() };
+                    if escaped.len() + piece.len() > STRING_SIZE {
+                        break;
+                    }
+                    escaped.push_str(&piece);
+                }
+                format!("{:<8}= {:<20}", keyword, format!("'{:<8}'", escaped))
+            },
+            Card::History(text) => format!("HISTORY {}", text),
+        };
+
+        let mut card = card
+            .chars()
+            .filter(|c| c.is_ascii() && !c.is_ascii_control())
+            .take(CARD_SIZE)
+            .collect::<String>();
+        while card.len() < CARD_SIZE {
+            card.push(' ');
+        }
+        card
+    }
+}
+
+fn format_float(value: f64) -> String {
+    format!("{:?}", value).to_uppercase()
+}
+
+// Writes an image to a FITS file. The data is stored plane by plane with values normalized to one,
+// rows are stored starting from the bottom of the image.
+pub fn write(path: &Path, width: usize, height: usize, planes: usize, data: &[f32], depth: Depth, cards: &[Card]) -> error::Result<()> {
+    debug_assert_eq!(data.len(), width * height * planes);
+
+    let mut header = vec![
+        Card::Logical("SIMPLE", true),
+        Card::Integer("BITPIX", match depth {
+            Depth::Integer => 16,
+            Depth::Float => -32,
+        }),
+        Card::Integer("NAXIS", if planes == 1 { 2 } else { 3 }),
+        Card::Integer("NAXIS1", width as i64),
+        Card::Integer("NAXIS2", height as i64),
+    ];
+    if planes != 1 {
+        header.push(Card::Integer("NAXIS3", planes as i64));
+    }
+    if depth == Depth::Integer {
+        header.push(Card::Integer("BZERO", 32768));
+        header.push(Card::Integer("BSCALE", 1));
+    }
+
+    let mut bytes = Vec::new();
+    for card in header.iter().chain(cards.iter()) {
+        bytes.extend_from_slice(card.format().as_bytes());
+    }
+    bytes.extend_from_slice(format!("{:<80}", "END").as_bytes());
+    pad(&mut bytes, b' ');
+
+    let area = width * height;
+    for plane in 0..planes {
+        for y in (0..height).rev() {
+            for x in 0..width {
+                let value = data[plane * area + y * width + x];
+                match depth {
+                    Depth::Integer => {
+                        let value = (value.max(0.0).min(1.0) * u16::max_value() as f32).round() as i32 - 32768;
+                        bytes.extend_from_slice(&(value as i16).to_be_bytes());
+                    },
+                    Depth::Float => {
+                        bytes.extend_from_slice(&value.to_be_bytes());
+                    }
+                }
+            }
+        }
+    }
+    pad(&mut bytes, 0);
+
+    fs::write(path, bytes)?;
+
+    Ok(())
+}
+
+// Pads the data to a multiple of the block size.
+fn pad(bytes: &mut Vec<u8>, value: u8) {
+    let length = (bytes.len() + BLOCK_SIZE - 1) / BLOCK_SIZE * BLOCK_SIZE;
+    bytes.resize(length, value);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Writes the data to a FITS file and reads it back.
+    fn round_trip(name: &str, width: usize, height: usize, planes: usize, data: &[f32], depth: Depth, cards: &[Card]) -> Fits {
+        let path = std::env::temp_dir().join(format!("strata-{}-{}.fits", name, std::process::id()));
+        write(&path, width, height, planes, data, depth, cards).unwrap();
+        let fits = Fits::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        fits
+    }
+
+    #[test]
+    fn float_data_is_taken_as_it_is() {
+        let data = [0.0, 0.25, 0.5, 1.0, 2.5, -0.5];
+        let fits = round_trip("float", 3, 2, 1, &data, Depth::Float, &[]);
+        assert_eq!(fits.bitpix, -32);
+        assert_eq!(fits.to_image().as_raw(), &data);
+    }
+
+    #[test]
+    fn float_data_is_divided_by_the_factor() {
+        let data = [0.0, 100.0, 65535.0, 32768.0];
+        let fits = round_trip("factor", 2, 2, 1, &data, Depth::Float, &[]);
+        let image = fits.to_image_divided(65535.0);
+        for (value, expected) in image.as_raw().iter().zip(data.iter()) {
+            assert!((value - expected / 65535.0).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn integer_data_is_normalized_by_its_type() {
+        let data = [0.0, 0.1, 0.5, 1.0, 0.75, 0.2];
+        let fits = round_trip("integer", 2, 3, 1, &data, Depth::Integer, &[]);
+        assert_eq!(fits.bitpix, 16);
+        for (value, expected) in fits.to_image().as_raw().iter().zip(data.iter()) {
+            assert!((value - expected).abs() <= 0.5 / 65535.0 + 1e-6);
+        }
+    }
+
+    #[test]
+    fn planes_are_stored_separately() {
+        // Two by one pixels with three planes, stored plane by plane.
+        let data = [0.1, 0.2, 0.3, 0.4, 0.5, 0.6];
+        let fits = round_trip("planes", 2, 1, 3, &data, Depth::Float, &[]);
+        let image = fits.to_image();
+        assert_eq!(image.channels(), 3);
+        assert_eq!(image.get_pixel(0, 0), &[0.1, 0.3, 0.5]);
+        assert_eq!(image.get_pixel(1, 0), &[0.2, 0.4, 0.6]);
+    }
+
+    #[test]
+    fn header_cards_are_written_and_read() {
+        let cards = [
+            Card::Integer("NCOMBINE", 12),
+            Card::Float("EXPTIME", 300.5),
+            Card::String("FILTER", "It's Ha".to_string()),
+            Card::History("Strata: Join".to_string()),
+        ];
+        let fits = round_trip("header", 1, 1, 1, &[0.5], Depth::Float, &cards);
+        assert_eq!(fits.header.get_i64("NCOMBINE"), Some(12));
+        assert_eq!(fits.header.get_f64("EXPTIME"), Some(300.5));
+        assert_eq!(fits.header.get("FILTER"), Some("It's Ha"));
+        assert_eq!(fits.metadata().exposure, Some(300.5));
+    }
+
+    #[test]
+    fn long_strings_are_cut_inside_the_quotes() {
+        // The escaped quote still fits, or is left out as a whole.
+        let card = Card::String("OBJECT", format!("{}'s nebula", "A".repeat(66))).format();
+        assert_eq!(card, format!("OBJECT  = '{}'''", "A".repeat(66)));
+
+        let card = Card::String("OBJECT", format!("{}'s nebula", "A".repeat(67))).format();
+        assert_eq!(card.len(), CARD_SIZE);
+        assert_eq!(card, format!("OBJECT  = '{}' ", "A".repeat(67)));
+
+        let fits = round_trip("long", 1, 1, 1, &[0.5], Depth::Float, &[Card::String("OBJECT", "B".repeat(100))]);
+        assert_eq!(fits.header.get("OBJECT"), Some("B".repeat(68).as_str()));
+    }
+
+    #[test]
+    fn non_finite_values_are_undefined() {
+        let card = Card::Float("DATAMAX", f64::NAN).format();
+        assert_eq!(card.len(), CARD_SIZE);
+        assert_eq!(card.trim_end(), "DATAMAX =");
+
+        let fits = round_trip("undefined", 1, 1, 1, &[0.5], Depth::Float, &[Card::Float("EXPTIME", f64::INFINITY)]);
+        assert_eq!(fits.header.get("EXPTIME"), Some(""));
+        assert_eq!(fits.header.get_f64("EXPTIME"), None);
+    }
+
+    #[test]
+    fn non_ascii_bytes_in_the_header_are_ignored() {
+        let path = std::env::temp_dir().join(format!("strata-ascii-{}.fits", std::process::id()));
+        write(&path, 1, 1, 1, &[0.5], Depth::Float, &[Card::String("OBSERVER", "x".to_string())]).unwrap();
+        let mut bytes = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        // Puts bytes that are not valid UTF-8 into the keyword and value of the last card.
+        let card = bytes
+            .chunks(CARD_SIZE)
+            .position(|card| card.starts_with(b"OBSERVER"))
+            .unwrap() * CARD_SIZE;
+        bytes[card + 7] = 0xc3;
+        bytes[card + 12] = 0xff;
+
+        let fits = Fits::parse(&bytes).unwrap();
+        assert_eq!(fits.width, 1);
+        assert_eq!(fits.to_image().as_raw(), &[0.5]);
+    }
+}
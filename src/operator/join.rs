@@ -2,7 +2,21 @@ use super::{
     Operation,
     View,
     Layer,
+    Metadata,
     Vector,
+    buffer::Image,
+    stars,
+    transform::{
+        Point,
+        Transform,
+    },
+    resample::Kernel,
+    strategy::{
+        Strategy,
+        Sample,
+        Resampled,
+        Mosaic,
+    },
     error,
 };
 use std::{
@@ -11,44 +25,229 @@ use std::{
         max,
     }
 };
-use image::{
-    RgbImage,
-    Rgb
-};
 use rayon::prelude::*;
 
+// Layers whose registration went wrong can be stretched far beyond their size. The canvas may not
+// be larger than this many times the area of all layers together.
+const MAXIMUM_CANVAS: f64 = 4.0;
+
 pub struct Join {
+    kernel: Kernel,
+    // Combines the layers covering each pixel.
+    strategy: Box<dyn Strategy>
 }
 
 impl Join {
     pub fn new() -> Join {
         Join {
+            kernel: Kernel::default(),
+            strategy: Box::new(Mosaic)
+        }
+    }
+
+    // Sets the interpolation used to resample the layers through their transforms.
+    pub fn kernel(mut self, kernel: Kernel) -> Join {
+        self.kernel = kernel;
+        self
+    }
+
+    // Sets how the layers are combined, for example blended as a mosaic or stacked.
+    pub fn strategy(mut self, strategy: impl Strategy + 'static) -> Join {
+        self.strategy = Box::new(strategy);
+        self
+    }
+
+    // Samples the layer at the given position in the coordinates of the reference, together with
+    // the distance of the position to the nearest edge of the layer.
+    fn sample(&self, layer: &Layer, point: &Point) -> Option<(Vec<f32>, f32)> {
+        let pixel = layer.sample(point, self.kernel)?;
+        let local = layer.transform.apply_inverse(point);
+        let edge = (local.x + 0.5)
+            .min(local.y + 0.5)
+            .min(layer.image.width() as f32 - 0.5 - local.x)
+            .min(layer.image.height() as f32 - 0.5 - local.y);
+        Some((pixel, edge))
+    }
+
+    // Resamples a layer onto its bounding box on the canvas of the given size, which the given
+    // transform maps the coordinates of the reference to.
+    fn resample(&self, layer: &Layer, weight: f32, canvas: &Transform, size: (u32, u32), channels: usize) -> Resampled {
+        let transform = layer.transform.then(canvas);
+        let (min, max) = transform.bounds(layer.image.width(), layer.image.height());
+        // The bounds run along the outer edges of the pixels, a pixel more on each side also keeps
+        // the border of interpolation kernels that reach beyond their centers.
+        let clamp = |value: f32, end: u32| value.max(0.0).min(end as f32) as usize;
+        let (left, top) = (clamp(min.x.floor() - 1.0, size.0), clamp(min.y.floor() - 1.0, size.1));
+        let (right, bottom) = (clamp(max.x.ceil() + 1.0, size.0), clamp(max.y.ceil() + 1.0, size.1));
+        let width = right.saturating_sub(left);
+
+        let mut image = Image::new(width as u32, bottom.saturating_sub(top) as u32, channels);
+        let mut edge = vec![f32::NEG_INFINITY; width * bottom.saturating_sub(top)];
+        image.as_raw_mut()
+            .par_chunks_mut(channels)
+            .zip(edge.par_iter_mut())
+            .enumerate()
+            .for_each(|(i, (target, edge))| {
+                let point = canvas.apply_inverse(&Point::new((left + i % width) as f32, (top + i / width) as f32));
+                if let Some((pixel, distance)) = self.sample(layer, &point) {
+                    for (c, value) in target.iter_mut().enumerate() {
+                        *value = pixel[c.min(pixel.len() - 1)];
+                    }
+                    *edge = distance;
+                }
+            });
+
+        Resampled {
+            image,
+            edge,
+            origin: (left, top),
+            weight,
+            transform,
+        }
+    }
+
+    // Matches the median and the noise of each channel of the layers to those of the reference by
+    // an offset and a factor.
+    fn normalize(view: &mut View) {
+        let statistics = |image: &Image| {
+            let channels = image.channels();
+            (0..channels)
+                .map(|c| stars::background(&image.as_raw().iter().skip(c).step_by(channels).cloned().collect::<Vec<f32>>()))
+                .collect::<Vec<(f32, f32)>>()
+        };
+        let image = &view.layers[view.reference.unwrap_or(0)].image;
+        let reference = statistics(image);
+        let luminance = stars::background(&stars::luminance(image));
+
+        view.layers
+            .par_iter_mut()
+            .for_each(|layer| {
+                let own = statistics(&layer.image);
+                let channels = layer.image.channels();
+                for (i, value) in layer.image.as_raw_mut().iter_mut().enumerate() {
+                    let (median, noise) = own[i % channels];
+                    // Mono layers are matched to the luminance of color references, all channels
+                    // of color layers to mono references.
+                    let (target, target_noise) = if channels == 1 {
+                        luminance
+                    } else {
+                        reference[(i % channels).min(reference.len() - 1)]
+                    };
+                    let factor = if noise > 0.0 { target_noise / noise } else { 1.0 };
+                    *value = (*value - median) * factor + target;
+                }
+            });
+    }
+
+    // Combines the metadata of all joined layers.
+    fn metadata(view: &View) -> Metadata {
+        let layers = &view.layers;
+        let exposures = layers
+            .iter()
+            .filter_map(|layer| layer.metadata.exposure)
+            .collect::<Vec<f64>>();
+        let filter = layers
+            .first()
+            .and_then(|layer| layer.metadata.filter.clone())
+            .filter(|filter| layers.iter().all(|layer| layer.metadata.filter.as_ref() == Some(filter)));
+        // Sensor settings are only kept if all layers share them.
+        let common = |value: fn(&Metadata) -> Option<f64>| layers
+            .first()
+            .and_then(|layer| value(&layer.metadata))
+            .filter(|first| layers.iter().all(|layer| value(&layer.metadata) == Some(*first)));
+
+        Metadata {
+            frames: layers.iter().map(|layer| layer.metadata.frames).sum(),
+            reference: view.reference.and_then(|i| layers[i].metadata.path.clone()),
+            exposure: if exposures.is_empty() { None } else { Some(exposures.iter().sum()) },
+            iso: common(|metadata| metadata.iso),
+            gain: common(|metadata| metadata.gain),
+            filter,
+            ..
+            Metadata::default()
         }
     }
 }
 
 impl Operation for Join {
-    fn apply(&self, view: View) -> error::Result<View> {
-        println!("Joining images ...");
+    fn apply(&self, mut view: View) -> error::Result<View> {
+        println!("Joining images by {} ...", self.strategy.name());
+
+        if self.strategy.normalize() && view.layers.len() > 1 {
+            println!("Normalizing {} images to the background and noise of the reference ...", view.layers.len());
+            Join::normalize(&mut view);
+        }
+
+        let weights = self.strategy.weights(&view.layers);
+
+        // The canvas may have a finer resolution than the reference, with the edges of the pixels
+        // of the reference on the edges of pixels of the canvas.
+        let scale = self.strategy.scale();
+        let scaling = Transform::similarity(scale, 0.0, (scale - 1.0) / 2.0, (scale - 1.0) / 2.0);
 
         let dimensions = view.layers
             .par_iter()
             .map(|layer| {
-                let l = layer.position.x;
-                let t = layer.position.y;
-                let r = layer.position.x + layer.image.width() as i32;
-                let b = layer.position.y + layer.image.height() as i32;
+                let (min, max) = layer.transform.then(&scaling).bounds(layer.image.width(), layer.image.height());
 
-                (l, t, r, b)
+                // The canvas takes all pixels that the edges of the layers reach into.
+                let (min, max) = (min.add_scalar(0.5), max.add_scalar(0.5));
+                (min.x.floor() as i32, min.y.floor() as i32, max.x.ceil() as i32, max.y.ceil() as i32)
             })
             .reduce(|| (i32::max_value(), i32::max_value(), i32::min_value(), i32::min_value()), |(l1, t1, r1, b1), (l2, t2, r2, b2)| {
                 (min(l1, l2), min(t1, t2), max(r1, r2), max(b1, b2))
             });
 
-        let size = (dimensions.2 - dimensions.0, dimensions.3 - dimensions.1);
+        let (width, height) = (dimensions.2 as i64 - dimensions.0 as i64, dimensions.3 as i64 - dimensions.1 as i64);
+        let area = view.layers
+            .iter()
+            .map(|layer| layer.image.width() as f64 * layer.image.height() as f64)
+            .sum::<f64>() * (scale as f64).powi(2);
+        if (width * height) as f64 > MAXIMUM_CANVAS * area || width > i32::max_value() as i64 || height > i32::max_value() as i64 {
+            return Err(error::Error::input(format!(
+                "The joined image would be {}x{} pixels, far larger than the layers, so some frames were not registered correctly.",
+                width, height
+            )));
+        }
+
+        let size = (width as i32, height as i32);
+        let channels = view.layers
+            .iter()
+            .map(|layer| layer.image.channels())
+            .max()
+            .unwrap_or(1);
+        let canvas = scaling.then(&Transform::translation(-dimensions.0 as f32, -dimensions.1 as f32));
 
         debug_assert!(size.0 > 0 && size.1 > 0);
 
+        // Strategies that drop the pixels of the layers onto the canvas also return a weight map.
+        if let Some((image, weights)) = self.strategy.scatter(&view.layers, &weights, &canvas, (size.0 as u32, size.1 as u32), channels) {
+            let mut layer = Layer::new(image);
+            layer.weights = Some(weights);
+            layer.metadata = Join::metadata(&view);
+
+            return Ok(View {
+                layers: vec![layer],
+                reference: Some(0),
+                ..
+                view
+            });
+        }
+
+        // Strategies that blend more than single pixels resample the layers themselves.
+        let resample = |k: usize| self.resample(&view.layers[k], weights[k], &canvas, (size.0 as u32, size.1 as u32), channels);
+        if let Some(image) = self.strategy.blend(&view.layers, &resample, (size.0 as u32, size.1 as u32)) {
+            let mut layer = Layer::new(image);
+            layer.metadata = Join::metadata(&view);
+
+            return Ok(View {
+                layers: vec![layer],
+                reference: Some(0),
+                ..
+                view
+            });
+        }
+
         let pixels = (dimensions.0..dimensions.2)
             .into_par_iter()
             .map(move |x| 
@@ -57,63 +256,87 @@ impl Operation for Join {
                     .map(move |y| Vector::new(x, y))
             )
             .flatten()
-            .map(|position| {
-                let colors = view.layers
-                    .par_iter()
-                    .enumerate()
-                    .map(|(i, layer)| {
-                        layer.get_pixel(&position)
-                            .map(|pixel| {
-                                let center = layer.position + Vector::new(layer.image.width() as i32, layer.image.height() as i32) / 2;
-                                let distance = position - center;
-                                (
-                                    /*
-                                    match i {
-                                        0 => (255.0, 0.0, 0.0),
-                                        1 => (255.0, 255.0, 0.0),
-                                        2 => (0.0, 255.0, 0.0),
-                                        3 => (0.0, 255.0, 255.0),
-                                        _ => unreachable!()
-                                    },
-                                    */
-                                    //((i % 2) as f64 * 255.0, (i % 4) as f64 * 255.0 / 3.0, (i % 8) as f64 * 255.0 / 7.0)
-
-                                    (pixel[0] as f64, pixel[1] as f64, pixel[2] as f64),
-                                    (1.0 / ((distance.x.pow(2) + distance.y.pow(2)) as f64).sqrt()).powf(4.0)
-                                )
+            // The buffers of each thread are reused for all its pixels and channels.
+            .map_init(|| (Vec::new(), Vec::new()), |(samples, buffer), position| {
+                let point = scaling.apply_inverse(&Point::new(position.x as f32, position.y as f32));
+                samples.clear();
+                samples.extend(view.layers
+                    .iter()
+                    .zip(weights.iter())
+                    .filter_map(|(layer, &weight)| self.sample(layer, &point).map(|(pixel, edge)| (pixel, weight, edge)))
+                );
+
+                let result = (0..channels)
+                    .map(|c| {
+                        // Pixels that are not covered by any layer stay black.
+                        if samples.is_empty() {
+                            return 0.0;
+                        }
+                        buffer.clear();
+                        buffer.extend(samples
+                            .iter()
+                            .map(|(pixel, weight, edge)| Sample {
+                                // Mono layers contribute equally to all channels.
+                                value: pixel[c.min(pixel.len() - 1)],
+                                weight: *weight,
+                                edge: *edge,
                             })
+                        );
+                        self.strategy.combine(buffer)
                     })
-                    .filter_map(|x| x)
-                    .collect::<Vec<((f64, f64, f64), f64)>>();
-                
-                let sum = colors
-                    .par_iter()
-                    .map(|(_, d)| d)
-                    .sum::<f64>();
-                
-                let result = colors
-                    .into_iter()
-                    .fold((0.0, 0.0, 0.0), |acc, (c, d)| {
-                        (acc.0 + c.0 * d, acc.1 + c.1 * d, acc.2 + c.2 * d)
-                    });
+                    .collect::<Vec<f32>>();
 
                 (
                     (position.x - dimensions.0) as u32,
-                    (position.y - dimensions.1) as u32, 
-                    ((result.0 / sum) as u8, (result.1 / sum) as u8, (result.2 / sum) as u8)
+                    (position.y - dimensions.1) as u32,
+                    result
                 )
             })
-            .collect::<Vec<(u32, u32, (u8, u8, u8))>>();
+            .collect::<Vec<(u32, u32, Vec<f32>)>>();
         
         // Put pixels into a new image.
-        let mut image = RgbImage::new(size.0 as u32, size.1 as u32);
+        let mut image = Image::new(size.0 as u32, size.1 as u32, channels);
         for pixel in pixels {
-            let color = pixel.2;
-            image.put_pixel(pixel.0, pixel.1, Rgb::from([color.0, color.1, color.2]));
+            image.get_pixel_mut(pixel.0, pixel.1).copy_from_slice(&pixel.2);
         }
 
+        let mut layer = Layer::new(image);
+        layer.metadata = Join::metadata(&view);
+
         Ok(View {
-            layers: vec![Layer::new(image)]
+            layers: vec![layer],
+            reference: Some(0),
+            ..
+            view
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Values around the given level of each channel that vary by the given amplitude.
+    fn layer(levels: &[f32], amplitude: f32) -> Layer {
+        let channels = levels.len();
+        let data = (0..(64 * 48 * channels))
+            .map(|i| levels[i % channels] + amplitude * (((i / channels) * 37 % 17) as f32 / 16.0 - 0.5))
+            .collect();
+        Layer::new(Image::from_raw(64, 48, channels, data))
+    }
+
+    #[test]
+    fn mono_layers_are_normalized_to_the_luminance_of_color_references() {
+        let mut view = View {
+            layers: vec![layer(&[0.1, 0.5, 0.3], 0.02), layer(&[0.7], 0.1)],
+            reference: Some(0),
+            ..View::default()
+        };
+        let luminance = stars::background(&stars::luminance(&view.layers[0].image));
+        Join::normalize(&mut view);
+
+        let (median, noise) = stars::background(view.layers[1].image.as_raw());
+        assert!((median - luminance.0).abs() < 1e-4, "median {} instead of {}", median, luminance.0);
+        assert!((noise - luminance.1).abs() < 1e-4, "noise {} instead of {}", noise, luminance.1);
+    }
+}
@@ -2,59 +2,142 @@ use super::{
     Operation,
     View,
     Layer,
-    error,
+    Metadata,
+    buffer::Image,
+    fits::{
+        self,
+        Fits,
+    },
+    raw::{
+        self,
+        Raw,
+    },
+    error::{
+        self,
+        Error,
+    },
 };
 use std::{
     fs,
-    path::PathBuf
-};
-use image::{
-    DynamicImage,
-    imageops::FilterType
+    io,
+    path::{
+        Path,
+        PathBuf,
+    },
 };
+use image::imageops::FilterType;
 use rayon::prelude::*;
 
 pub struct Load {
     path: PathBuf,
-    preview: bool
+    preview: bool,
+    skip_invalid: bool,
+    // Floating point FITS data is divided by this factor.
+    fits_scale: f64
 }
 
 impl Load {
     pub fn new(path: PathBuf) -> Load {
         Load {
             path,
-            preview: false
+            preview: false,
+            skip_invalid: false,
+            fits_scale: 1.0
         }
     }
+
+    // Downscales the images to speed up processing.
+    pub fn preview(mut self, preview: bool) -> Load {
+        self.preview = preview;
+        self
+    }
+
+    // Skips files that cannot be read instead of failing.
+    pub fn skip_invalid(mut self, skip_invalid: bool) -> Load {
+        self.skip_invalid = skip_invalid;
+        self
+    }
+
+    // Sets the factor floating point FITS data is divided by, the same for all files so that their
+    // values stay comparable. Integer data is always normalized by the range of its type.
+    pub fn fits_scale(mut self, factor: f64) -> Load {
+        self.fits_scale = factor;
+        self
+    }
+
+    fn load(&self, path: &Path) -> error::Result<Layer> {
+        let (mut image, pattern, metadata) = if fits::is_fits(path) {
+            let fits = Fits::read(path)?;
+            (fits.to_image_divided(self.fits_scale), fits.pattern(), fits.metadata())
+        } else if raw::is_raw(path) {
+            let raw = Raw::read(path)?;
+            (raw.image, Some(raw.pattern), raw.metadata)
+        } else {
+            (Image::from_dynamic(&image::open(path)?), None, raw::read_exif(path))
+        };
+        // Resampling a mosaic would mix the colors of neighbouring photosites.
+        if self.preview && pattern.is_none() {
+            image = image.resize(512, 512, FilterType::Gaussian);
+        }
+
+        let mut layer = Layer::new(image);
+        layer.pattern = pattern;
+        layer.metadata = Metadata {
+            path: Some(path.to_path_buf()),
+            ..
+            metadata
+        };
+
+        Ok(layer)
+    }
 }
 
 impl Operation for Load {
+    fn settings(&self) -> String {
+        format!("preview {} fits-scale {}", self.preview, self.fits_scale)
+    }
+
     fn apply(&self, mut view: View) -> error::Result<View> {
         println!("Loading images from \"{}\" ...", self.path.display());
 
-        view.layers = fs::read_dir(&self.path)
-            .unwrap()
-            .into_iter()
-            .map(|path| path.unwrap().path())
-            .collect::<Vec<PathBuf>>()
+        let mut paths = fs::read_dir(&self.path)
+            .map_err(|error| Error::from(error).file(&self.path))?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<io::Result<Vec<PathBuf>>>()
+            .map_err(|error| Error::from(error).file(&self.path))?;
+        // Hidden files are not images.
+        paths.retain(|path| path.is_file() && !path
+            .file_name()
+            .map_or(false, |name| name.to_string_lossy().starts_with('.'))
+        );
+        paths.sort();
+
+        view.layers = paths
             .par_iter()
-            .map(|path| {
-                let mut result = image::open(path).unwrap();
-                if self.preview {
-                    result = result.resize(512, 512, FilterType::Gaussian);
+            .map(|path| match self.load(path) {
+                Ok(layer) => {
+                    println!("Finished loading image \"{}\"", path.display());
+                    Ok(Some(layer))
+                },
+                Err(error) => {
+                    let error = error.file(path);
+                    if self.skip_invalid {
+                        println!("Skipping {}", error);
+                        Ok(None)
+                    } else {
+                        Err(error)
+                    }
                 }
-                println!("Finished loading image \"{}\"", path.display());
-                result
             })
-            .map(|image|
-                if let DynamicImage::ImageRgb8(image) = image {
-                    Layer::new(image)
-                } else {
-                    panic!()
-                }
-            )
-            .collect::<Vec<Layer>>();
+            .collect::<error::Result<Vec<Option<Layer>>>>()?
+            .into_iter()
+            .filter_map(|layer| layer)
+            .collect();
+
+        if view.layers.is_empty() {
+            return Err(Error::input(format!("No images found in \"{}\".", self.path.display())));
+        }
 
         Ok(view)
     }
-}
\ No newline at end of file
+}
@@ -1,41 +1,129 @@
 mod load;
+mod calibration;
+mod cosmetic;
+mod debayer;
 mod position;
+mod asterism;
+mod adjustment;
+mod phase;
+mod sidecar;
 //mod sharpness;
 mod join;
+mod multiband;
+mod drizzle;
+mod selection;
 mod save;
 mod colors;
 
 pub mod error;
+pub mod fits;
+pub mod buffer;
+pub mod cfa;
+pub mod raw;
+pub mod statistics;
+pub mod stars;
+pub mod transform;
+pub mod resample;
+pub mod strategy;
 
-use load::Load;
-use position::Position;
-//use sharpness::Sharpness;
-use join::Join;
-use save::Save;
-use colors::Colors;
+pub use load::Load;
+pub use calibration::{
+    Calibration,
+    Master,
+};
+pub use cosmetic::Cosmetic;
+pub use debayer::{
+    Debayer,
+    Algorithm,
+    demosaic,
+};
+pub use position::{
+    Position,
+    Method,
+    Reference,
+    Pairing,
+};
+//pub use sharpness::Sharpness;
+pub use selection::{
+    Selection,
+    Quality,
+};
+pub use join::Join;
+pub use save::Save;
+pub use colors::Colors;
 
-use image::{
-    RgbImage,
-    Rgb
+use buffer::Image;
+use cfa::Pattern;
+use transform::{
+    Transform,
+    Point,
 };
+use resample::Kernel;
 
-use std::path::PathBuf;
+use std::{
+    path::PathBuf,
+    collections::HashMap,
+};
 use nalgebra::Vector2;
 
 pub type Vector = Vector2<i32>;
 
+// Acquisition information of a layer, as far as it is known from the input file.
+#[derive(Clone, Debug)]
+pub struct Metadata {
+    pub path: Option<PathBuf>,
+    // Number of frames that were integrated into this layer.
+    pub frames: usize,
+    // The frame all other frames were aligned to, only known for integrated layers.
+    pub reference: Option<PathBuf>,
+    pub exposure: Option<f64>,
+    pub iso: Option<f64>,
+    pub gain: Option<f64>,
+    pub temperature: Option<f64>,
+    pub date: Option<String>,
+    pub filter: Option<String>,
+    // All header keywords of the input file.
+    pub keywords: HashMap<String, String>,
+}
+
+impl Default for Metadata {
+    fn default() -> Metadata {
+        Metadata {
+            path: None,
+            frames: 1,
+            reference: None,
+            exposure: None,
+            iso: None,
+            gain: None,
+            temperature: None,
+            date: None,
+            filter: None,
+            keywords: HashMap::new(),
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct Layer {
-    pub position: Vector,
-    pub image: RgbImage,
+    // Maps the pixel coordinates of the layer to the coordinates of the reference.
+    pub transform: Transform,
+    pub image: Image,
+    // Color filter array of undemosaiced sensor data, the image is mono in this case.
+    pub pattern: Option<Pattern>,
+    pub metadata: Metadata,
+    // Total weight of the data in each pixel, for joined layers whose coverage varies.
+    pub weights: Option<Image>,
     //pub sharpness: Vec<f32>
 }
 
 impl Layer {
-    fn new(image: RgbImage) -> Layer {
+    pub fn new(image: Image) -> Layer {
         Layer {
             image,
-            position: Vector::zeros(),
+            transform: Transform::identity(),
+            pattern: None,
+            metadata: Metadata::default(),
+            weights: None,
             /*sharpness: {
                 let mut vec = Vec::new();
                 for _ in image.pixels() {
@@ -46,29 +134,40 @@ impl Layer {
         }
     }
     
-    fn get_pixel(&self, position: &Vector) -> Option<&Rgb<u8>> {
-        let absolute_position = position - self.position;
-
-        if absolute_position.x >= 0
-            && absolute_position.y >= 0
-            && absolute_position.x < self.image.width() as i32
-            && absolute_position.y < self.image.height() as i32
-        {
-            Some(self.image.get_pixel(absolute_position.x as u32, absolute_position.y as u32))
-            //Some(Rgb::from([self.position.x as u8 * 32, self.position.y as u8 * 32, self.position.x as u8 * self.position.y as u8]))
-        } else {
-            None
-        }
+    // Interpolates the layer at the given position in the coordinates of the reference.
+    pub fn sample(&self, position: &Point, kernel: Kernel) -> Option<Vec<f32>> {
+        let local = self.transform.apply_inverse(position);
+        resample::sample(&self.image, local.x, local.y, kernel)
     }
 }
 
 pub trait Operation {
     fn apply(&self, view: View) -> error::Result<View>;
+
+    // The name of the operation, as recorded in the history of a view.
+    fn name(&self) -> &'static str {
+        std::any::type_name::<Self>()
+            .rsplit("::")
+            .next()
+            .unwrap_or("Operation")
+    }
+
+    // Describes the parameters that change the result of the operation, as recorded in the
+    // settings of a view.
+    fn settings(&self) -> String {
+        String::new()
+    }
 }
 
 #[derive(Clone, Default)]
 pub struct View {
-    pub layers: Vec<Layer>
+    pub layers: Vec<Layer>,
+    // Index of the layer the other layers are aligned to.
+    pub reference: Option<usize>,
+    // Names of the operations that were applied to this view.
+    pub history: Vec<String>,
+    // Names and parameters of the operations that were applied to this view.
+    pub settings: Vec<String>
 }
 
 #[derive(Default)]
@@ -82,26 +181,179 @@ impl Operator {
         self.operations.push(Box::new(operation));
     }
 
-    pub fn run(&self) -> error::Result<()> {
-        let mut view = View::default();
-
+    // Applies all operations in order to the given view.
+    pub fn apply(&self, mut view: View) -> error::Result<View> {
         for operation in &self.operations {
             view = operation.apply(view)?;
+            view.history.push(operation.name().to_string());
+            view.settings.push(format!("{}: {}", operation.name(), operation.settings()));
         }
 
+        Ok(view)
+    }
+
+    pub fn run(&self) -> error::Result<()> {
+        self.apply(View::default())?;
+
         Ok(())
     }
 }
 
-pub async fn run(input: PathBuf, output: PathBuf) -> error::Result<()> {
+// Options that control how the pipeline is assembled.
+#[derive(Clone, Debug, Default)]
+pub struct Options {
+    pub preview: bool,
+    pub skip_invalid: bool,
+    // Factor floating point FITS data is divided by, the data is taken as it is if none.
+    pub fits_scale: Option<f64>,
+    // Directories containing calibration frames.
+    pub bias: Option<PathBuf>,
+    pub dark: Option<PathBuf>,
+    pub flat: Option<PathBuf>,
+    pub dark_flat: Option<PathBuf>,
+    // Directory in which master calibration frames are cached.
+    pub masters: Option<PathBuf>,
+    pub scale_dark: bool,
+    // Enables the correction of hot and cold pixels.
+    pub cosmetic: bool,
+    // Master dark FITS file and defect map used to find additional defective pixels.
+    pub cosmetic_dark: Option<PathBuf>,
+    pub defects: Option<PathBuf>,
+    pub debayer: Algorithm,
+    // Overrides the color filter pattern of the input files.
+    pub pattern: Option<Pattern>,
+    pub registration: Method,
+    // The frame all other frames are aligned to.
+    pub reference: Reference,
+    // Which pairs of frames are matched and with how many neighbours, the default of Position if none.
+    pub pairing: Pairing,
+    pub neighbours: Option<usize>,
+    // Also searches each frame rotated by 180 degrees when registering by differences or phases.
+    pub meridian_flip: bool,
+    // File in which the registration is kept for later runs, none by default.
+    pub registration_cache: Option<PathBuf>,
+    // Quality thresholds below which frames are rejected before registration, none by default.
+    pub min_stars: Option<usize>,
+    pub max_fwhm: Option<f32>,
+    pub max_eccentricity: Option<f32>,
+    pub max_background: Option<f32>,
+    pub max_noise: Option<f32>,
+    pub min_snr: Option<f32>,
+    // Percentage of the best frames that is kept.
+    pub keep_best: Option<f32>,
+    // Degrees of freedom of the transforms between frames when registering by stars.
+    pub transform: transform::Model,
+    // Minimum overlap and match confidence of registered pairs, the defaults of Position if none.
+    pub min_overlap: Option<f32>,
+    pub min_confidence: Option<f32>,
+    // Interpolation used to resample the layers when joining.
+    pub interpolation: Kernel,
+    // How the layers are joined and, when stacking, how outliers are rejected.
+    pub join: strategy::Mode,
+    pub integration: strategy::Integration,
+    // Cuts the overlaps of multi-band blended layers along optimal seams.
+    pub seam: bool,
+    // Rejection thresholds below and above the center, the defaults of Join if none.
+    pub rejection_low: Option<f32>,
+    pub rejection_high: Option<f32>,
+    // Resolution relative to the reference and size of the drops when drizzling, the defaults of
+    // Drizzle if none.
+    pub drizzle_scale: Option<f32>,
+    pub pixfrac: Option<f32>,
+    pub depth: fits::Depth
+}
+
+// Assembles the default pipeline from the input directory to the output file.
+pub fn build(input: PathBuf, output: PathBuf, options: &Options) -> Operator {
     let mut operator = Operator::default();
-    operator.add(Load::new(input));
-    operator.add(Position::new());
+    let fits_scale = options.fits_scale.unwrap_or(1.0);
+    operator.add(Load::new(input.clone())
+        .preview(options.preview)
+        .skip_invalid(options.skip_invalid)
+        .fits_scale(fits_scale));
+    let calibration = Calibration::new()
+        .bias(options.bias.clone())
+        .dark(options.dark.clone())
+        .flat(options.flat.clone())
+        .dark_flat(options.dark_flat.clone())
+        .cache(options.masters.clone())
+        .scale_dark(options.scale_dark)
+        .fits_scale(fits_scale)
+        .preview(options.preview);
+    if !calibration.is_empty() {
+        operator.add(calibration);
+    }
+    if options.cosmetic {
+        operator.add(Cosmetic::new()
+            .dark(options.cosmetic_dark.clone())
+            .defects(options.defects.clone()));
+    }
+    operator.add(Debayer::new(options.debayer).pattern(options.pattern));
+    let selection = Selection::new()
+        .min_stars(options.min_stars)
+        .max_fwhm(options.max_fwhm)
+        .max_eccentricity(options.max_eccentricity)
+        .max_background(options.max_background)
+        .max_noise(options.max_noise)
+        .min_snr(options.min_snr)
+        .best(options.keep_best);
+    if !selection.is_empty() {
+        operator.add(selection);
+    }
+    let mut position = Position::new()
+        .method(options.registration)
+        .model(options.transform)
+        .reference(options.reference.clone())
+        .pairing(options.pairing)
+        .flip(options.meridian_flip)
+        .cache(options.registration_cache.clone());
+    if let Some(neighbours) = options.neighbours {
+        position = position.neighbours(neighbours);
+    }
+    if let Some(overlap) = options.min_overlap {
+        position = position.overlap(overlap);
+    }
+    if let Some(confidence) = options.min_confidence {
+        position = position.confidence(confidence);
+    }
+    operator.add(position);
     operator.add(Colors::new());
     //operator.add(Sharpness::new());
-    operator.add(Join::new());
-    operator.add(Save::new(output));
-    operator.run()?;
+    let join = Join::new().kernel(options.interpolation);
+    operator.add(match options.join {
+        strategy::Mode::Mosaic => join.strategy(strategy::Mosaic),
+        strategy::Mode::Multiband => join.strategy(strategy::Multiband::new().seam(options.seam)),
+        strategy::Mode::Stack => {
+            let mut stack = strategy::Stack::new(options.integration);
+            if let Some(low) = options.rejection_low {
+                stack = stack.low(low);
+            }
+            if let Some(high) = options.rejection_high {
+                stack = stack.high(high);
+            }
+            join.strategy(stack)
+        },
+        strategy::Mode::Weighted => join.strategy(strategy::Weighted),
+        strategy::Mode::Maximum => join.strategy(strategy::Maximum),
+        strategy::Mode::Minimum => join.strategy(strategy::Minimum),
+        strategy::Mode::Drizzle => {
+            let mut drizzle = strategy::Drizzle::new();
+            if let Some(scale) = options.drizzle_scale {
+                drizzle = drizzle.scale(scale);
+            }
+            if let Some(pixfrac) = options.pixfrac {
+                drizzle = drizzle.pixfrac(pixfrac);
+            }
+            join.strategy(drizzle)
+        },
+    });
+    operator.add(Save::new(output).depth(options.depth));
+
+    operator
+}
 
-    Ok(())
+// Runs the default pipeline. It does not wait for anything, but the graphical interface runs it as
+// a command, which takes a future.
+pub async fn run(input: PathBuf, output: PathBuf) -> error::Result<()> {
+    build(input, output, &Options::default()).run()
 }
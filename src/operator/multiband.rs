@@ -0,0 +1,446 @@
+use super::{
+    Layer,
+    buffer::Image,
+    stars,
+    strategy::{
+        Strategy,
+        Sample,
+        Resampled,
+        Mosaic,
+    },
+    transform::Point,
+};
+use rayon::prelude::*;
+
+// Binomial kernel that approximates a gaussian for the pyramids.
+const KERNEL: [f32; 5] = [1.0 / 16.0, 4.0 / 16.0, 6.0 / 16.0, 4.0 / 16.0, 1.0 / 16.0];
+// Cost of a seam through a star, far above any difference between the layers.
+const STAR_COST: f32 = 1000.0;
+
+// A single channel of an image, or a mask.
+#[derive(Clone)]
+struct Plane {
+    width: usize,
+    height: usize,
+    data: Vec<f32>,
+}
+
+impl Plane {
+    fn from_fn(width: usize, height: usize, value: impl Fn(usize) -> f32 + Sync + Send) -> Plane {
+        Plane {
+            width,
+            height,
+            data: (0..(width * height)).into_par_iter().map(value).collect(),
+        }
+    }
+
+    // The value at the given position, repeating the border outside.
+    fn get(&self, x: i64, y: i64) -> f32 {
+        let x = x.max(0).min(self.width as i64 - 1) as usize;
+        let y = y.max(0).min(self.height as i64 - 1) as usize;
+        self.data[y * self.width + x]
+    }
+
+    // Blurs the plane and keeps every second pixel.
+    fn reduce(&self) -> Plane {
+        let (width, height) = ((self.width + 1) / 2, (self.height + 1) / 2);
+        Plane::from_fn(width, height, |i| {
+            let (x, y) = (2 * (i % width) as i64, 2 * (i / width) as i64);
+            let mut sum = 0.0;
+            for (dy, ky) in KERNEL.iter().enumerate() {
+                for (dx, kx) in KERNEL.iter().enumerate() {
+                    sum += ky * kx * self.get(x + dx as i64 - 2, y + dy as i64 - 2);
+                }
+            }
+            sum
+        })
+    }
+
+    // Interpolates the plane to the given size, the inverse of reduce.
+    fn expand(&self, width: usize, height: usize) -> Plane {
+        Plane::from_fn(width, height, |i| {
+            let (x, y) = ((i % width) as f32 / 2.0, (i / width) as f32 / 2.0);
+            let (x0, y0) = (x.floor(), y.floor());
+            let (fx, fy) = (x - x0, y - y0);
+            let (x0, y0) = (x0 as i64, y0 as i64);
+            let top = self.get(x0, y0) * (1.0 - fx) + self.get(x0 + 1, y0) * fx;
+            let bottom = self.get(x0, y0 + 1) * (1.0 - fx) + self.get(x0 + 1, y0 + 1) * fx;
+            top * (1.0 - fy) + bottom * fy
+        })
+    }
+
+    fn zip(&self, other: &Plane, operation: impl Fn(f32, f32) -> f32 + Sync + Send) -> Plane {
+        Plane::from_fn(self.width, self.height, |i| operation(self.data[i], other.data[i]))
+    }
+
+    // Adds the other plane, placed with its top left corner at the given position.
+    fn add(&mut self, x: usize, y: usize, other: &Plane) {
+        let width = self.width;
+        self.data
+            .par_chunks_mut(width)
+            .skip(y)
+            .zip(other.data.par_chunks(other.width))
+            .for_each(|(row, values)| {
+                for (value, other) in row[x..(x + other.width)].iter_mut().zip(values) {
+                    *value += other;
+                }
+            });
+    }
+}
+
+// Successively blurred and reduced copies of the plane, starting with the plane itself.
+fn gaussian(plane: Plane, levels: usize) -> Vec<Plane> {
+    let mut pyramid = vec![plane];
+    while pyramid.len() < levels {
+        let next = pyramid[pyramid.len() - 1].reduce();
+        pyramid.push(next);
+    }
+    pyramid
+}
+
+// The differences between the levels of the gaussian pyramid, each holding one band of
+// frequencies, and the coarsest level.
+fn laplacian(plane: Plane, levels: usize) -> Vec<Plane> {
+    let gaussian = gaussian(plane, levels);
+    let mut pyramid = gaussian
+        .windows(2)
+        .map(|pair| pair[0].zip(&pair[1].expand(pair[0].width, pair[0].height), |a, b| a - b))
+        .collect::<Vec<Plane>>();
+    pyramid.push(gaussian[levels - 1].clone());
+    pyramid
+}
+
+// Adds up the bands of a laplacian pyramid.
+fn collapse(pyramid: Vec<Plane>) -> Plane {
+    let mut levels = pyramid.into_iter().rev();
+    let coarsest = levels.next().expect("pyramids have at least one level");
+    levels.fold(coarsest, |result, level| level.zip(&result.expand(level.width, level.height), |a, b| a + b))
+}
+
+// Replaces the values where the coverage is zero by values extrapolated from the covered
+// surroundings, so that the black outside of a layer does not bleed into the low frequencies.
+fn fill(plane: &Plane, coverage: &Plane) -> Plane {
+    if plane.width <= 1 && plane.height <= 1 {
+        return plane.clone();
+    }
+
+    let premultiplied = plane.zip(coverage, |value, coverage| value * coverage).reduce();
+    let reduced = coverage.reduce();
+    let normalized = premultiplied.zip(&reduced, |value, coverage| if coverage > 0.0 { value / coverage } else { 0.0 });
+    let coarse = fill(&normalized, &reduced).expand(plane.width, plane.height);
+
+    Plane::from_fn(plane.width, plane.height, |i| if coverage.data[i] > 0.0 { plane.data[i] } else { coarse.data[i] })
+}
+
+// Blends the panels of a mosaic band by band. Low frequencies, such as differences in sky
+// brightness, are merged over wide transitions, while high frequencies like stars are merged over
+// narrow ones and stay sharp. Each pixel is taken from the layer whose edges are furthest away,
+// or with an optimal seam, from the side of a path through the overlap along which the layers
+// agree best and which avoids stars.
+pub struct Multiband {
+    levels: usize,
+    seam: bool,
+}
+
+impl Multiband {
+    pub fn new() -> Multiband {
+        Multiband {
+            levels: 6,
+            seam: false,
+        }
+    }
+
+    // Number of frequency bands, each one covering half the frequencies of the previous one.
+    pub fn levels(mut self, levels: usize) -> Multiband {
+        self.levels = levels.max(1);
+        self
+    }
+
+    pub fn seam(mut self, seam: bool) -> Multiband {
+        self.seam = seam;
+        self
+    }
+
+    // Assigns each pixel of the canvas to the layer whose edges are furthest away.
+    fn nearest(resampled: &[Resampled], width: usize, height: usize) -> Vec<Option<usize>> {
+        (0..(width * height))
+            .into_par_iter()
+            .map(|i| resampled
+                .iter()
+                .map(|layer| layer.edge_at(i % width, i / width))
+                .enumerate()
+                .filter(|(_, edge)| edge.is_finite())
+                .max_by(|(_, a), (_, b)| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal))
+                .map(|(k, _)| k)
+            )
+            .collect()
+    }
+
+    // Adds the layers one after another, cutting the overlap of each new layer with the layers
+    // before it along the path of the smallest difference, which stays clear of stars.
+    fn seams(layers: &[Layer], resampled: &[Resampled], width: usize, height: usize) -> Vec<Option<usize>> {
+        // Stars of all layers are marked on the canvas with a margin around them.
+        let mut stars = vec![false; width * height];
+        for (layer, resampled) in layers.iter().zip(resampled) {
+            for star in stars::detect(&layer.image, 5.0) {
+                let center = resampled.transform.apply(&Point::new(star.x, star.y));
+                let radius = (2.0 * star.fwhm).max(3.0);
+                let (x0, x1) = ((center.x - radius).floor().max(0.0) as usize, (center.x + radius).ceil().max(0.0) as usize);
+                let (y0, y1) = ((center.y - radius).floor().max(0.0) as usize, (center.y + radius).ceil().max(0.0) as usize);
+                for y in y0..y1.min(height) {
+                    for x in x0..x1.min(width) {
+                        if (Point::new(x as f32, y as f32) - center).norm() <= radius {
+                            stars[y * width + x] = true;
+                        }
+                    }
+                }
+            }
+        }
+
+        let mut labels: Vec<Option<usize>> = vec![None; width * height];
+        // Luminance of the layer that each pixel is assigned to, and the sum and number of the
+        // positions of all assigned pixels.
+        let mut existing = vec![0.0; width * height];
+        let (mut assigned, mut count) = (Point::zeros(), 0.0);
+
+        for (k, layer) in resampled.iter().enumerate() {
+            let (columns, rows) = (layer.image.width() as usize, layer.image.height() as usize);
+            let luminance = stars::luminance(&layer.image);
+            // Index on the canvas and position of the pixels of the bounding box of the layer.
+            let canvas = |i: usize| (layer.origin.1 + i / columns) * width + layer.origin.0 + i % columns;
+            let position = |i: usize| Point::new((layer.origin.0 + i % columns) as f32, (layer.origin.1 + i / columns) as f32);
+            let overlap = (0..(columns * rows))
+                .map(|i| layer.edge[i].is_finite() && labels[canvas(i)].is_some())
+                .collect::<Vec<bool>>();
+
+            if overlap.iter().any(|&overlap| overlap) {
+                // The new layer keeps the side of the seam that faces its own center.
+                let (sum, covered) = (0..(columns * rows))
+                    .filter(|&i| layer.edge[i].is_finite())
+                    .fold((Point::zeros(), 0.0), |(sum, count), i| (sum + position(i), count + 1.0));
+                let direction = sum / covered - assigned / count;
+
+                // The seam runs across the direction between the layers, in rows of u along v.
+                let across = direction.x.abs() >= direction.y.abs();
+                let index = |u: usize, v: usize| if across { v * columns + u } else { u * columns + v };
+                let (columns, rows) = if across { (columns, rows) } else { (rows, columns) };
+                let positive = if across { direction.x > 0.0 } else { direction.y > 0.0 };
+                let cost = |i: usize| (existing[canvas(i)] - luminance[i]).abs() + if stars[canvas(i)] { STAR_COST } else { 0.0 };
+
+                // Dynamic programming finds the cheapest connected path from row to row. Overlaps
+                // that are not connected to the previous row start anew.
+                let mut totals = vec![f32::INFINITY; columns * rows];
+                for v in 0..rows {
+                    for u in (0..columns).filter(|&u| overlap[index(u, v)]) {
+                        let best = match v.checked_sub(1) {
+                            Some(p) => (u.saturating_sub(1)..=(u + 1).min(columns - 1))
+                                .map(|w| totals[p * columns + w])
+                                .fold(f32::INFINITY, f32::min),
+                            None => 0.0
+                        };
+                        totals[v * columns + u] = cost(index(u, v)) + if best.is_finite() { best } else { 0.0 };
+                    }
+                }
+
+                // Traces the path back from the cheapest end in each connected run of rows.
+                let cheapest = |v: usize, range: std::ops::RangeInclusive<usize>| range
+                    .filter(|&u| totals[v * columns + u].is_finite())
+                    .min_by(|&a, &b| totals[v * columns + a].partial_cmp(&totals[v * columns + b]).unwrap_or(std::cmp::Ordering::Equal));
+                let mut seam = vec![None; rows];
+                let mut current: Option<usize> = None;
+                for v in (0..rows).rev() {
+                    current = match current {
+                        Some(u) => cheapest(v, u.saturating_sub(1)..=(u + 1).min(columns - 1)),
+                        None => None
+                    }.or_else(|| cheapest(v, 0..=(columns - 1)));
+                    seam[v] = current;
+                }
+
+                for v in 0..rows {
+                    if let Some(s) = seam[v] {
+                        for u in (0..columns).filter(|&u| overlap[index(u, v)]) {
+                            if (positive && u > s) || (!positive && u < s) {
+                                labels[canvas(index(u, v))] = Some(k);
+                                existing[canvas(index(u, v))] = luminance[index(u, v)];
+                            }
+                        }
+                    }
+                }
+            }
+
+            for i in (0..overlap.len()).filter(|&i| layer.edge[i].is_finite() && !overlap[i]) {
+                labels[canvas(i)] = Some(k);
+                existing[canvas(i)] = luminance[i];
+                assigned += position(i);
+                count += 1.0;
+            }
+        }
+
+        labels
+    }
+}
+
+impl Strategy for Multiband {
+    fn name(&self) -> String {
+        if self.seam {
+            format!("multi-band blending of {} bands along optimal seams", self.levels)
+        } else {
+            format!("multi-band blending of {} bands", self.levels)
+        }
+    }
+
+    // Single pixels are blended like a mosaic.
+    fn combine(&self, samples: &mut Vec<Sample>) -> f32 {
+        Mosaic.combine(samples)
+    }
+
+    fn blend(&self, layers: &[Layer], resample: &(dyn Fn(usize) -> Resampled + Sync), size: (u32, u32)) -> Option<Image> {
+        // Within their bounding boxes, the resampled layers take about as much memory as the layers.
+        let resampled = (0..layers.len())
+            .into_par_iter()
+            .map(resample)
+            .collect::<Vec<Resampled>>();
+        let (width, height, channels) = (size.0 as usize, size.1 as usize, resampled[0].image.channels());
+        // The coarsest band must still have a few pixels.
+        let levels = self.levels.min((width.min(height) as f32 / 4.0).log2().max(1.0) as usize);
+
+        let labels = if self.seam {
+            Multiband::seams(layers, &resampled, width, height)
+        } else {
+            Multiband::nearest(&resampled, width, height)
+        };
+
+        let zeros = || gaussian(Plane::from_fn(width, height, |_| 0.0), levels);
+        let mut bands = vec![zeros(); channels];
+        let mut totals = zeros();
+
+        // The pyramids of each layer only span its bounding box, aligned to the pixels of the coarsest
+        // level and extended by the distance the blurred masks spread beyond it.
+        let step = 1 << (levels - 1);
+        let margin = 4 << levels;
+
+        for (k, layer) in resampled.iter().enumerate() {
+            let (columns, rows) = (layer.image.width() as usize, layer.image.height() as usize);
+            let (left, top) = (layer.origin.0.saturating_sub(margin) / step * step, layer.origin.1.saturating_sub(margin) / step * step);
+            let (right, bottom) = ((layer.origin.0 + columns + margin).min(width), (layer.origin.1 + rows + margin).min(height));
+            let (span, lines) = (right - left, bottom - top);
+
+            // Index within the bounding box of the layer of each pixel of the region.
+            let inside = |i: usize| {
+                let (x, y) = (left + i % span, top + i / span);
+                let (x, y) = (x.checked_sub(layer.origin.0)?, y.checked_sub(layer.origin.1)?);
+                if x < columns && y < rows { Some(y * columns + x) } else { None }
+            };
+            let mask = Plane::from_fn(span, lines, |i| if labels[(top + i / span) * width + left + i % span] == Some(k) { 1.0 } else { 0.0 });
+            if !mask.data.iter().any(|&mask| mask > 0.0) {
+                continue;
+            }
+            let coverage = Plane::from_fn(span, lines, |i| match inside(i) {
+                Some(j) if layer.edge[j].is_finite() => 1.0,
+                _ => 0.0
+            });
+            let masks = gaussian(mask, levels);
+
+            for (c, bands) in bands.iter_mut().enumerate() {
+                let plane = Plane::from_fn(span, lines, |i| inside(i).map_or(0.0, |j| layer.image.as_raw()[j * channels + c]));
+                let pyramid = laplacian(fill(&plane, &coverage), levels);
+                for (l, (band, (level, mask))) in bands.iter_mut().zip(pyramid.iter().zip(&masks)).enumerate() {
+                    band.add(left >> l, top >> l, &level.zip(mask, |value, mask| value * mask));
+                }
+            }
+            for (l, (total, mask)) in totals.iter_mut().zip(&masks).enumerate() {
+                total.add(left >> l, top >> l, mask);
+            }
+        }
+
+        // The masks blurred near the border of the canvas do not add up to one.
+        let results = bands
+            .into_iter()
+            .map(|bands| collapse(bands
+                .iter()
+                .zip(&totals)
+                .map(|(band, total)| band.zip(total, |value, total| if total > 1e-6 { value / total } else { 0.0 }))
+                .collect()
+            ))
+            .collect::<Vec<Plane>>();
+
+        // Pixels that are not covered by any layer stay black.
+        let data = (0..(width * height * channels))
+            .into_par_iter()
+            .map(|i| if labels[i / channels].is_some() { results[i % channels].data[i / channels] } else { 0.0 })
+            .collect();
+
+        Some(Image::from_raw(width as u32, height as u32, channels, data))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::transform::Transform;
+
+    const SIZE: (usize, usize) = (120, 48);
+    // Columns of the canvas covered by each layer, the layers overlap between 50 and 70.
+    const COLUMNS: [(usize, usize); 2] = [(0, 70), (50, 120)];
+
+    // A layer covering the given columns of the canvas with values of the scene at each position.
+    fn resampled(k: usize, scene: impl Fn(usize, usize) -> f32) -> Resampled {
+        let (left, right) = COLUMNS[k];
+        let (columns, rows) = (right - left, SIZE.1);
+        let data = (0..(columns * rows)).map(|i| scene(left + i % columns, i / columns)).collect();
+        let edge = (0..(columns * rows))
+            .map(|i| {
+                let (x, y) = ((i % columns) as f32, (i / columns) as f32);
+                (x + 0.5).min(y + 0.5).min(columns as f32 - 0.5 - x).min(rows as f32 - 0.5 - y)
+            })
+            .collect();
+        Resampled {
+            image: Image::from_raw(columns as u32, rows as u32, 1, data),
+            edge,
+            origin: (left, 0),
+            weight: 1.0,
+            transform: Transform::translation(left as f32, 0.0),
+        }
+    }
+
+    fn layers(scene: impl Fn(usize, usize) -> f32 + Copy) -> Vec<Layer> {
+        (0..2).map(|k| Layer::new(resampled(k, scene).image)).collect()
+    }
+
+    // Varies across the canvas, the same in both layers.
+    fn scene(x: usize, y: usize) -> f32 {
+        0.3 + 0.05 * ((x * 7 + y * 13) % 11) as f32 / 10.0
+    }
+
+    #[test]
+    fn constant_layers_blend_to_a_constant() {
+        let constant = |_, _| 0.3;
+        for &seam in &[false, true] {
+            let image = Multiband::new()
+                .seam(seam)
+                .blend(&layers(constant), &|k| resampled(k, constant), (SIZE.0 as u32, SIZE.1 as u32))
+                .unwrap();
+            for (i, value) in image.as_raw().iter().enumerate() {
+                assert!((value - 0.3).abs() < 1e-4, "{} at {} {} with seam {}", value, i % SIZE.0, i / SIZE.0, seam);
+            }
+        }
+    }
+
+    #[test]
+    fn seams_stay_inside_the_overlap() {
+        let resampled = (0..2).map(|k| resampled(k, scene)).collect::<Vec<Resampled>>();
+        for labels in &[
+            Multiband::nearest(&resampled, SIZE.0, SIZE.1),
+            Multiband::seams(&layers(scene), &resampled, SIZE.0, SIZE.1),
+        ] {
+            for y in 0..SIZE.1 {
+                let row = &labels[(y * SIZE.0)..((y + 1) * SIZE.0)];
+                // Each row switches once from the first to the second layer, within the overlap.
+                let switch = row.iter().position(|&label| label == Some(1)).unwrap();
+                assert!(switch >= COLUMNS[1].0 && switch <= COLUMNS[0].1, "seam at {} in row {}", switch, y);
+                assert!(row[..switch].iter().all(|&label| label == Some(0)));
+                assert!(row[switch..].iter().all(|&label| label == Some(1)));
+            }
+        }
+    }
+}
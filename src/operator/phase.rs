@@ -0,0 +1,302 @@
+use super::{
+    buffer::Image,
+    stars,
+    transform::Point,
+};
+use std::{
+    f32::consts::PI,
+    sync::{Arc, Mutex},
+};
+use rayon::prelude::*;
+use rustfft::{
+    Fft,
+    FftPlanner,
+    num_complex::Complex,
+};
+
+// Largest side of the binned images that are correlated to find the coarse offset.
+const SIZE: usize = 512;
+
+// A single channel image as plain values.
+struct Plane {
+    width: usize,
+    height: usize,
+    data: Vec<f32>,
+}
+
+impl Plane {
+    // Averages the channels and bins squares of the given size into one value.
+    fn from_image(image: &Image, factor: usize) -> Plane {
+        let luminance = stars::luminance(image);
+        let (width, height) = (image.width() as usize, image.height() as usize);
+        let (binned_width, binned_height) = (width / factor, height / factor);
+
+        let data = (0..(binned_width * binned_height))
+            .into_par_iter()
+            .map(|i| {
+                let (x, y) = ((i % binned_width) * factor, (i / binned_width) * factor);
+                let sum = (y..(y + factor))
+                    .flat_map(|y| (x..(x + factor)).map(move |x| (x, y)))
+                    .map(|(x, y)| luminance[y * width + x])
+                    .sum::<f32>();
+                sum / (factor * factor) as f32
+            })
+            .collect();
+
+        Plane {
+            width: binned_width,
+            height: binned_height,
+            data,
+        }
+    }
+
+    fn crop(&self, x: usize, y: usize, width: usize, height: usize) -> Plane {
+        let data = (y..(y + height))
+            .flat_map(|y| self.data[(y * self.width + x)..(y * self.width + x + width)].iter().cloned())
+            .collect();
+
+        Plane {
+            width,
+            height,
+            data,
+        }
+    }
+
+    // Removes the mean, applies a Hann window against edge effects and pads the plane with zeros
+    // to the given size.
+    fn prepare(&self, width: usize, height: usize) -> Vec<Complex<f32>> {
+        let mean = self.data.iter().sum::<f32>() / self.data.len().max(1) as f32;
+        let window = |i: usize, size: usize| 0.5 - 0.5 * (2.0 * PI * (i as f32 + 0.5) / size as f32).cos();
+
+        let mut result = vec![Complex::new(0.0, 0.0); width * height];
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let value = (self.data[y * self.width + x] - mean) * window(x, self.width) * window(y, self.height);
+                result[y * width + x] = Complex::new(value, 0.0);
+            }
+        }
+        result
+    }
+}
+
+// The one dimensional transforms of the rows and columns of data of a given size.
+struct Plan {
+    width: usize,
+    height: usize,
+    rows: Arc<dyn Fft<f32>>,
+    columns: Arc<dyn Fft<f32>>,
+}
+
+impl Plan {
+    // Transforms the rows and then the columns of the data in place.
+    fn process(&self, data: &mut [Complex<f32>]) {
+        let (width, height) = (self.width, self.height);
+        data.par_chunks_mut(width).for_each(|row| self.rows.process(row));
+
+        let mut transposed = (0..(width * height))
+            .into_par_iter()
+            .map(|i| data[(i % height) * width + i / height])
+            .collect::<Vec<Complex<f32>>>();
+        transposed.par_chunks_mut(height).for_each(|column| self.columns.process(column));
+
+        data.par_iter_mut()
+            .enumerate()
+            .for_each(|(i, value)| *value = transposed[(i % width) * height + i / width]);
+    }
+}
+
+// Finds offsets between images by phase correlation. The planner keeps the transforms of each
+// size, so they are planned once and reused for all pairs of images.
+pub struct Correlator {
+    planner: Mutex<FftPlanner<f32>>,
+}
+
+impl Correlator {
+    pub fn new() -> Correlator {
+        Correlator {
+            planner: Mutex::new(FftPlanner::new()),
+        }
+    }
+
+    // Returns the forward and inverse plans for data of the given size.
+    fn plans(&self, width: usize, height: usize) -> (Plan, Plan) {
+        let mut planner = self.planner.lock().unwrap_or_else(|error| error.into_inner());
+        let forward = Plan {
+            width,
+            height,
+            rows: planner.plan_fft_forward(width),
+            columns: planner.plan_fft_forward(height),
+        };
+        let inverse = Plan {
+            width,
+            height,
+            rows: planner.plan_fft_inverse(width),
+            columns: planner.plan_fft_inverse(height),
+        };
+        (forward, inverse)
+    }
+
+    // Correlates the phases of two planes and returns the shift of the second one relative to the
+    // first and the confidence of the match between 0 and 1, which compares the correlation peak to
+    // the highest value away from it. The planes are padded to the given size, shifts are found
+    // modulo this size.
+    fn correlate(&self, first: &Plane, second: &Plane, width: usize, height: usize) -> (Point, f32) {
+        let (forward, inverse) = self.plans(width, height);
+        let (mut a, mut b) = (first.prepare(width, height), second.prepare(width, height));
+        forward.process(&mut a);
+        forward.process(&mut b);
+
+        // The normalized cross power spectrum only keeps the phase differences.
+        let mut spectrum = a
+            .par_iter()
+            .zip(b.par_iter())
+            .map(|(a, b)| {
+                let product = a * b.conj();
+                let norm = product.norm();
+                if norm > f32::EPSILON { product / norm } else { Complex::new(0.0, 0.0) }
+            })
+            .collect::<Vec<Complex<f32>>>();
+        inverse.process(&mut spectrum);
+
+        let area = (width * height) as f32;
+        let value = |x: usize, y: usize| spectrum[(y % height) * width + x % width].re / area;
+        let (peak, _) = spectrum
+            .iter()
+            .enumerate()
+            .max_by(|(_, a), (_, b)| a.re.partial_cmp(&b.re).unwrap_or(std::cmp::Ordering::Equal))
+            .unwrap_or((0, &Complex::new(0.0, 0.0)));
+        let (px, py) = (peak % width, peak / width);
+        let center = value(px, py);
+
+        // A parabola through the neighbours of the peak interpolates its position between pixels.
+        let interpolate = |before: f32, after: f32| {
+            let curvature = before - 2.0 * center + after;
+            if curvature < 0.0 {
+                ((before - after) / (2.0 * curvature)).max(-0.5).min(0.5)
+            } else {
+                0.0
+            }
+        };
+        // The second highest peak excludes the neighbourhood of the first one.
+        let distance = |a: usize, b: usize, size: usize| {
+            let d = if a > b { a - b } else { b - a };
+            d.min(size - d)
+        };
+        let second = spectrum
+            .par_iter()
+            .enumerate()
+            .filter(|(i, _)| distance(i % width, px, width).pow(2) + distance(i / width, py, height).pow(2) > 4)
+            .map(|(_, value)| value.re / area)
+            .reduce(|| 0.0, f32::max);
+        let confidence = if center > 0.0 { (1.0 - second / center).max(0.0).min(1.0) } else { 0.0 };
+
+        let dx = interpolate(value(px + width - 1, py), value(px + 1, py));
+        let dy = interpolate(value(px, py + height - 1), value(px, py + 1));
+
+        // Shifts beyond half of the size wrap around to negative shifts.
+        let unwrap = |position: usize, size: usize| if position > size / 2 {
+            position as f32 - size as f32
+        } else {
+            position as f32
+        };
+
+        (Point::new(unwrap(px, width) + dx, unwrap(py, height) + dy), confidence)
+    }
+
+    // Finds the position of the second image relative to the first one by phase correlation. The
+    // offset is first found on binned images padded to avoid wrapping around, then refined on the
+    // overlapping parts at full resolution. Returns the offset and the confidence of the match.
+    pub fn register(&self, i1: &Image, i2: &Image) -> (Point, f32) {
+        let largest = [i1.width(), i1.height(), i2.width(), i2.height()]
+            .iter()
+            .cloned()
+            .max()
+            .unwrap_or(1) as usize;
+        let factor = ((largest + SIZE - 1) / SIZE).max(1);
+
+        let (first, second) = (Plane::from_image(i1, factor), Plane::from_image(i2, factor));
+        let width = (first.width + second.width).next_power_of_two();
+        let height = (first.height + second.height).next_power_of_two();
+        let (offset, confidence) = self.correlate(&first, &second, width, height);
+        let offset = offset * factor as f32;
+
+        if factor == 1 {
+            return (offset, confidence);
+        }
+
+        // The overlap of both images at full resolution, limited in size.
+        let (first, second) = (Plane::from_image(i1, 1), Plane::from_image(i2, 1));
+        let (ox, oy) = (offset.x.round() as i64, offset.y.round() as i64);
+        let left = ox.max(0);
+        let top = oy.max(0);
+        let right = (first.width as i64).min(second.width as i64 + ox);
+        let bottom = (first.height as i64).min(second.height as i64 + oy);
+        let size = (2 * SIZE) as i64;
+        let (width, height) = ((right - left).min(size), (bottom - top).min(size));
+        if width < 16 || height < 16 {
+            return (offset, confidence);
+        }
+        let (x, y) = (left + (right - left - width) / 2, top + (bottom - top - height) / 2);
+
+        let a = first.crop(x as usize, y as usize, width as usize, height as usize);
+        let b = second.crop((x - ox) as usize, (y - oy) as usize, width as usize, height as usize);
+        let (residual, _) = self.correlate(&a, &b, width as usize, height as usize);
+
+        // The residual must be within the precision of the binned search.
+        if residual.x.abs() <= factor as f32 && residual.y.abs() <= factor as f32 {
+            (Point::new(ox as f32, oy as f32) + residual, confidence)
+        } else {
+            (offset, confidence)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SIZE: (u32, u32) = (600, 400);
+
+    // Blobs scattered from a fixed seed, seen by a frame whose coordinates are shifted by the offset
+    // relative to the scene.
+    fn frame(offset: Point) -> Image {
+        let mut state = 4321u32;
+        let mut random = move || {
+            state = state.wrapping_mul(1664525).wrapping_add(1013904223);
+            (state >> 8) as f32 / (1 << 24) as f32
+        };
+        let blobs = (0..60)
+            .map(|_| (random() * SIZE.0 as f32, random() * SIZE.1 as f32, 2.0 + 4.0 * random(), random()))
+            .collect::<Vec<(f32, f32, f32, f32)>>();
+
+        let data = (0..(SIZE.0 * SIZE.1))
+            .into_par_iter()
+            .map(|i| {
+                let (x, y) = ((i % SIZE.0) as f32 + offset.x, (i / SIZE.0) as f32 + offset.y);
+                blobs
+                    .iter()
+                    .map(|&(bx, by, sigma, peak)| {
+                        peak * (-((x - bx).powi(2) + (y - by).powi(2)) / (2.0 * sigma * sigma)).exp()
+                    })
+                    .sum::<f32>()
+            })
+            .collect();
+        Image::from_raw(SIZE.0, SIZE.1, 1, data)
+    }
+
+    fn assert_recovered(offset: Point, tolerance: f32) {
+        let (found, confidence) = Correlator::new().register(&frame(Point::zeros()), &frame(offset));
+        assert!((found - offset).norm() < tolerance, "found {:?} instead of {:?}", found, offset);
+        assert!(confidence > 0.5, "confidence is {}", confidence);
+    }
+
+    #[test]
+    fn integer_shifts_are_recovered() {
+        assert_recovered(Point::new(37.0, -21.0), 0.05);
+    }
+
+    #[test]
+    fn sub_pixel_shifts_are_recovered() {
+        assert_recovered(Point::new(-12.3, 8.6), 0.1);
+    }
+}
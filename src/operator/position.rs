@@ -2,29 +2,51 @@ use super::{
     Operation,
     View,
     Vector,
+    buffer::Image,
+    stars,
+    asterism,
+    adjustment,
+    phase,
+    statistics,
+    selection::Quality,
+    sidecar::{
+        Frame,
+        Registration,
+    },
+    transform::{
+        Model,
+        Point,
+        Transform,
+    },
     error
 };
 use std::{
     iter::FromIterator,
+    path::{
+        Path,
+        PathBuf,
+    },
     cmp::{
         min,
-        max
+        max,
+        Ordering,
     }
 };
-use image::{
-    RgbImage
-};
 use rayon::prelude::*;
 use partitions::PartitionVec;
 
-// Calculates the difference between two images.
-fn image_difference(i1: &RgbImage, i2: &RgbImage, i2_rel_to_i1: &Vector, density: u32) -> f32 {
+// Calculates the difference between two images. Offsets at which the images overlap by less
+// than the minimum area in pixels are infinitely different.
+fn image_difference(i1: &Image, i2: &Image, i2_rel_to_i1: &Vector, density: u32, minimum: i64) -> f32 {
     let p1 = (max(0, i2_rel_to_i1.x), max(0, i2_rel_to_i1.y));
     let p2 = (max(0, -i2_rel_to_i1.x), max(0, -i2_rel_to_i1.y));
     let size = (
         min(i1.width() as i32 - i2_rel_to_i1.x, i2.width() as i32 + i2_rel_to_i1.x),
         min(i1.height() as i32 - i2_rel_to_i1.y, i2.height() as i32 + i2_rel_to_i1.y)
     );
+    if size.0 <= 0 || size.1 <= 0 || (size.0 as i64 * size.1 as i64) < minimum.max(1) {
+        return f32::INFINITY;
+    }
     let result = (0..size.0)
         .into_par_iter()
         .filter(|i| i % density as i32 == 0)
@@ -36,13 +58,12 @@ fn image_difference(i1: &RgbImage, i2: &RgbImage, i2_rel_to_i1: &Vector, density
         )
         .flatten()
         .map(|(x, y)| {
-            let a = i1.get_pixel((x + p1.0) as u32, (y + p1.1) as u32);
-            let b = i2.get_pixel((x + p2.0) as u32, (y + p2.1) as u32);
-            let error = (
-                ((a[0] as f32 - b[0] as f32) / 256.0).powi(2) + 
-                ((a[1] as f32 - b[1] as f32) / 256.0).powi(2) + 
-                ((a[2] as f32 - b[2] as f32) / 256.0).powi(2)
-            ).sqrt();
+            let (x1, y1) = ((x + p1.0) as u32, (y + p1.1) as u32);
+            let (x2, y2) = ((x + p2.0) as u32, (y + p2.1) as u32);
+            let error = (0..max(i1.channels(), i2.channels()))
+                .map(|c| (i1.get_channel(x1, y1, c) - i2.get_channel(x2, y2, c)).powi(2))
+                .sum::<f32>()
+                .sqrt();
             (error, 1)
         })
         .reduce(|| (0.0, 1), |acc, e| ((acc.0 + e.0), (acc.1 + e.1)));
@@ -52,101 +73,766 @@ fn image_difference(i1: &RgbImage, i2: &RgbImage, i2_rel_to_i1: &Vector, density
     result
 }
 
+// Searches the position of the second image relative to the first one with the smallest
+// difference, coarse to fine, and refines it to fractions of a pixel. The images must overlap by
+// the given fraction of the smaller image. The confidence compares the smallest difference to the
+// typical difference of the coarsest search.
+fn search(i1: &Image, i2: &Image, overlap: f32) -> (Point, f32) {
+    let mut px = -(min(i1.width(), i2.width()) as i32 / 2);
+    let mut rx = max(i1.width(), i2.width())  as i32;
+    let mut py = -(min(i1.height(), i2.height()) as i32 / 2);
+    let mut ry = max(i1.height(), i2.height())  as i32;
+    let mut result = (Vector::zeros(), f32::INFINITY);
+    let mut typical = f32::INFINITY;
+
+    let minimum = (overlap * min(
+        i1.width() as i64 * i1.height() as i64,
+        i2.width() as i64 * i2.height() as i64
+    ) as f32) as i64;
+
+    let r =
+        (min(
+            min(i1.width(), i1.height()), 
+            min(i2.width(), i2.height())
+        ) as f32 / 32.0).log2() as u32;
+
+    for g in (0..=r).map(|x| 2_u32.pow(r - x)) {
+
+        let differences = (px..(px + rx))
+            .into_par_iter()
+            .filter(move |i| i % g as i32 == 0)
+            .map(move |x| 
+                (py..(py + ry))
+                    .into_par_iter()
+                    .filter(move |i| i % g as i32 == 0)
+                    .map(move |y| Vector::new(x, y))
+            )
+            .flatten()
+            // Iterates through all possible image positions.
+            .map(move |i2_rel_to_i1| {
+                (i2_rel_to_i1, image_difference(
+                    i1, 
+                    i2,
+                    &i2_rel_to_i1,
+                    g,
+                    minimum
+                ))
+            })
+            .collect::<Vec<(Vector, f32)>>();
+
+        result = differences
+            .iter()
+            .cloned()
+            .fold((Vector::zeros(), f32::INFINITY), |a, b| {
+                if a.1 <= b.1 {
+                    a
+                } else {
+                    b
+                }
+            });
+
+        if typical.is_infinite() {
+            let mut finite = differences
+                .iter()
+                .map(|(_, difference)| *difference)
+                .filter(|difference| difference.is_finite())
+                .collect::<Vec<f32>>();
+            typical = statistics::median(&mut finite);
+        }
+
+        px = result.0.x - g as i32;
+        rx = g as i32 * 2;
+        py = result.0.y - g as i32;
+        ry = g as i32 * 2;
+    }
+
+    if !result.1.is_finite() {
+        return (Point::zeros(), 0.0);
+    }
+
+    // A parabola through the differences of the neighbouring offsets locates the minimum between pixels.
+    let refine = |step: Vector| {
+        let before = image_difference(i1, i2, &(result.0 - step), 1, minimum);
+        let after = image_difference(i1, i2, &(result.0 + step), 1, minimum);
+        let curvature = before - 2.0 * result.1 + after;
+        if curvature > 0.0 && curvature.is_finite() {
+            ((before - after) / (2.0 * curvature)).max(-0.5).min(0.5)
+        } else {
+            0.0
+        }
+    };
+    let offset = Point::new(
+        result.0.x as f32 + refine(Vector::new(1, 0)),
+        result.0.y as f32 + refine(Vector::new(0, 1))
+    );
+
+    let confidence = if typical > 0.0 {
+        (1.0 - result.1 / typical).max(0.0).min(1.0)
+    } else {
+        0.0
+    };
+
+    (offset, confidence)
+}
+
+// Estimates the fraction of the smaller of two frames that is covered by the other one, where the
+// transform maps the coordinates of the second frame to the first.
+fn overlap(transform: &Transform, first: (u32, u32), second: (u32, u32)) -> f32 {
+    const SAMPLES: u32 = 16;
+    let inside = (0..SAMPLES)
+        .flat_map(|y| (0..SAMPLES).map(move |x| (x, y)))
+        .map(|(x, y)| transform.apply(&Point::new(
+            (x as f32 + 0.5) * second.0 as f32 / SAMPLES as f32,
+            (y as f32 + 0.5) * second.1 as f32 / SAMPLES as f32
+        )))
+        .filter(|point| point.x >= 0.0 && point.y >= 0.0 && point.x < first.0 as f32 && point.y < first.1 as f32)
+        .count();
+
+    let (area1, area2) = (first.0 as f32 * first.1 as f32, second.0 as f32 * second.1 as f32);
+    (inside as f32 / (SAMPLES * SAMPLES) as f32 * area2 / area1.min(area2)).min(1.0)
+}
+
+// Side of the tiny thumbnails that describe frames for similar pairing.
+const DESCRIPTOR: usize = 16;
+
+// A tiny thumbnail of a frame with zero mean and unit norm, which is quick to compare with those of
+// many other frames. Frames of the same field are close to each other, dithering by a fraction of
+// a cell hardly changes it.
+struct Descriptor(Vec<f32>);
+
+impl Descriptor {
+    fn new(image: &Image) -> Descriptor {
+        let luminance = stars::luminance(image);
+        let (width, height) = (image.width() as usize, image.height() as usize);
+
+        let mut cells = vec![(0.0, 0); DESCRIPTOR * DESCRIPTOR];
+        for (i, value) in luminance.iter().enumerate().filter(|(_, value)| value.is_finite()) {
+            let (x, y) = ((i % width) * DESCRIPTOR / width, (i / width) * DESCRIPTOR / height);
+            let cell = &mut cells[y * DESCRIPTOR + x];
+            *cell = (cell.0 + value, cell.1 + 1);
+        }
+        let mut values = cells
+            .iter()
+            .map(|&(sum, count)| if count > 0 { sum / count as f32 } else { 0.0 })
+            .collect::<Vec<f32>>();
+
+        let mean = values.iter().sum::<f32>() / values.len() as f32;
+        let norm = values.iter().map(|value| (value - mean).powi(2)).sum::<f32>().sqrt();
+        for value in values.iter_mut() {
+            *value = if norm > 0.0 { (*value - mean) / norm } else { 0.0 };
+        }
+        Descriptor(values)
+    }
+
+    fn distance(&self, other: &Descriptor) -> f32 {
+        self.0
+            .iter()
+            .zip(other.0.iter())
+            .map(|(a, b)| (a - b).powi(2))
+            .sum()
+    }
+}
+
+// A registered pair of images.
+#[derive(Clone, Copy, Debug)]
+struct Pair {
+    first: usize,
+    second: usize,
+    // Maps the coordinates of the second image to those of the first.
+    transform: Transform,
+    // Between 0 for matches no better than chance and 1 for certain matches.
+    confidence: f32,
+}
+
+// Methods to find the positions of the images relative to each other.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Method {
+    // Minimizes the difference of the overlapping parts of two images.
+    Difference,
+    // Correlates the phases of the Fourier transforms, much faster than searching the difference.
+    Phase,
+    // Matches triangles of detected stars.
+    Stars,
+    // Leaves all frames in place, for cameras that did not move, such as for star trails.
+    None,
+}
+
+impl Method {
+    pub fn parse(name: &str) -> Option<Method> {
+        match name.to_lowercase().as_str() {
+            "difference" => Some(Method::Difference),
+            "phase" => Some(Method::Phase),
+            "stars" => Some(Method::Stars),
+            "none" => Some(Method::None),
+            _ => None
+        }
+    }
+}
+
+impl Default for Method {
+    fn default() -> Method {
+        Method::Difference
+    }
+}
+
+// Which pairs of frames are matched. Matching all pairs finds the best tree but takes quadratic
+// time, the others scale linearly with the number of frames.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Pairing {
+    All,
+    // Each frame with the reference frame only.
+    Reference,
+    // Each frame with its neighbours in the order of acquisition.
+    Sequential,
+    // Each frame with the frames whose tiny thumbnails are closest.
+    Similar,
+}
+
+impl Pairing {
+    pub fn parse(name: &str) -> Option<Pairing> {
+        match name.to_lowercase().as_str() {
+            "all" => Some(Pairing::All),
+            "reference" => Some(Pairing::Reference),
+            "sequential" => Some(Pairing::Sequential),
+            "similar" => Some(Pairing::Similar),
+            _ => None
+        }
+    }
+}
+
+impl Default for Pairing {
+    fn default() -> Pairing {
+        Pairing::All
+    }
+}
+
+// The frame all other frames are aligned to.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Reference {
+    // The frame with the best quality, see Quality::score.
+    Automatic,
+    // The frame at the given position, counting from 0, among the frames that are registered in
+    // the order of loading.
+    Index(usize),
+    // The frame loaded from the given path, or with the given file name.
+    Path(PathBuf),
+}
+
+impl Reference {
+    // Parses "#N" as index, otherwise existing files, relative to the working or the given input
+    // directory, take precedence over the keyword "auto" and plain numbers used as index.
+    pub fn parse(value: &str, directory: &Path) -> Reference {
+        if let Some(index) = value.strip_prefix('#').and_then(|index| index.parse().ok()) {
+            return Reference::Index(index);
+        }
+        let path = PathBuf::from(value);
+        if path.exists() || directory.join(&path).exists() {
+            return Reference::Path(path);
+        }
+        match value.parse() {
+            Ok(index) => Reference::Index(index),
+            Err(_) if value == "auto" => Reference::Automatic,
+            Err(_) => Reference::Path(path)
+        }
+    }
+
+    // Finds the index of an explicitly chosen reference frame.
+    fn find(&self, view: &View) -> error::Result<Option<usize>> {
+        match self {
+            Reference::Automatic => Ok(None),
+            Reference::Index(index) => if *index < view.layers.len() {
+                Ok(Some(*index))
+            } else {
+                Err(error::Error::input(format!("There is no frame #{} to use as reference.", index)))
+            },
+            Reference::Path(path) => {
+                // Relative and absolute paths of the same file are compared as canonical paths.
+                let canonical = |path: &Path| path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+                let reference = canonical(path);
+                view.layers
+                    .iter()
+                    .position(|layer| match &layer.metadata.path {
+                        Some(other) => canonical(other) == reference
+                            || (path.components().count() == 1 && other.file_name() == path.file_name()),
+                        None => false
+                    })
+                    .map(Some)
+                    .ok_or_else(|| error::Error::input(format!("Reference frame \"{}\" is not among the input frames.", path.display())))
+            }
+        }
+    }
+}
+
+impl Default for Reference {
+    fn default() -> Reference {
+        Reference::Automatic
+    }
+}
+
+// Display name of a layer in progress messages.
+pub(super) fn name(view: &View, i: usize) -> String {
+    view.layers[i].metadata.path
+        .as_ref()
+        .map(|path| path.display().to_string())
+        .unwrap_or_else(|| format!("#{}", i))
+}
+
 pub struct Position {
+    method: Method,
+    // Refines the transforms over all pairs after building the tree.
+    adjust: bool,
+    // Degrees of freedom of the transforms found by star matching.
+    model: Model,
+    // Detection threshold for stars in standard deviations of the background noise.
+    sigma: f32,
+    // Fraction of the smaller image two images must share to be matched.
+    overlap: f32,
+    // Pairs with a lower confidence are not used.
+    confidence: f32,
+    reference: Reference,
+    pairing: Pairing,
+    // Number of neighbours each frame is matched with for sequential and similar pairing.
+    neighbours: usize,
+    // Also searches the second image of each pair rotated by 180 degrees. Star matching finds
+    // rotations by itself.
+    flip: bool,
+    // File in which the results are kept for the next run with the same frames.
+    cache: Option<PathBuf>
 }
 
 impl Position {
     pub fn new() -> Position {
         Position {
+            method: Method::default(),
+            adjust: true,
+            model: Model::default(),
+            sigma: 5.0,
+            overlap: 0.1,
+            confidence: 0.2,
+            reference: Reference::default(),
+            pairing: Pairing::default(),
+            neighbours: 3,
+            flip: false,
+            cache: None
         }
     }
-}
 
-impl Operation for Position {
-    fn apply(&self, mut view: View) -> error::Result<View> {
-        println!("Finding relative positions of images ...");
+    pub fn method(mut self, method: Method) -> Position {
+        self.method = method;
+        self
+    }
 
-        // Matches contains a vector with the positions of the images relative to each other.
-        let mut matches = view.layers
-            .par_iter()
-            .enumerate()
-            .map(|(n1, l1)|
-                view.layers
-                    .par_iter()
+    pub fn adjust(mut self, adjust: bool) -> Position {
+        self.adjust = adjust;
+        self
+    }
+
+    pub fn model(mut self, model: Model) -> Position {
+        self.model = model;
+        self
+    }
+
+    pub fn sigma(mut self, sigma: f32) -> Position {
+        self.sigma = sigma;
+        self
+    }
+
+    pub fn overlap(mut self, overlap: f32) -> Position {
+        self.overlap = overlap;
+        self
+    }
+
+    pub fn confidence(mut self, confidence: f32) -> Position {
+        self.confidence = confidence;
+        self
+    }
+
+    pub fn reference(mut self, reference: Reference) -> Position {
+        self.reference = reference;
+        self
+    }
+
+    pub fn pairing(mut self, pairing: Pairing) -> Position {
+        self.pairing = pairing;
+        self
+    }
+
+    pub fn neighbours(mut self, neighbours: usize) -> Position {
+        self.neighbours = neighbours.max(1);
+        self
+    }
+
+    pub fn flip(mut self, flip: bool) -> Position {
+        self.flip = flip;
+        self
+    }
+
+    pub fn cache(mut self, path: Option<PathBuf>) -> Position {
+        self.cache = path;
+        self
+    }
+
+    // Describes everything that changes the result apart from the files of the frames, including
+    // the size of the layers and the operations that prepared them.
+    fn key(&self, view: &View) -> String {
+        let sizes = view.layers
+            .iter()
+            .map(|layer| (layer.image.width(), layer.image.height(), layer.image.channels()))
+            .collect::<Vec<(u32, u32, usize)>>();
+        format!(
+            "{:?} {:?} {} {} {} {:?} {:?} {} {} {} {:?} {:?}",
+            self.method, self.model, self.sigma, self.overlap, self.confidence,
+            self.reference, self.pairing, self.neighbours, self.flip, self.adjust, sizes, view.settings
+        )
+    }
+
+    // Applies the results of a previous run if they were found for the same frames and settings.
+    fn restore(&self, view: &mut View, frames: &[Frame]) -> bool {
+        let path = match &self.cache {
+            Some(path) => path,
+            None => return false
+        };
+        let registration = match Registration::read(path) {
+            Some(registration) => registration,
+            None => return false
+        };
+        if registration.frames != frames || registration.settings != self.key(view) {
+            println!("Registration cache \"{}\" is outdated.", path.display());
+            return false;
+        }
+
+        println!("Using cached registration \"{}\".", path.display());
+        for (first, second, confidence) in &registration.pairs {
+            println!(
+                "Pair \"{}\" and \"{}\" was matched with a confidence of {:.2}.",
+                name(view, *first), name(view, *second), confidence
+            );
+        }
+        for (i, transform) in registration.transforms.iter().enumerate() {
+            if transform.is_none() {
+                println!("Image \"{}\" could not be connected to the other images and is left out.", name(view, i));
+            }
+        }
+
+        view.reference = Some(registration.transforms[..registration.reference]
+            .iter()
+            .filter(|transform| transform.is_some())
+            .count()
+        );
+        view.layers = std::mem::take(&mut view.layers)
+            .into_iter()
+            .zip(registration.transforms)
+            .filter_map(|(mut layer, transform)| transform.map(|transform| {
+                layer.transform = transform;
+                layer
+            }))
+            .collect();
+        true
+    }
+
+    // Selects the pairs of layers to match, each with the lower index first. The hub is the frame
+    // all others are paired with for reference pairing.
+    fn candidates(&self, view: &View, hub: usize) -> Vec<(usize, usize)> {
+        let count = view.layers.len();
+        let mut candidates = match self.pairing {
+            Pairing::All => (0..count)
+                .flat_map(|n1| ((n1 + 1)..count).map(move |n2| (n1, n2)))
+                .collect::<Vec<(usize, usize)>>(),
+            Pairing::Reference => (0..count)
+                .filter(|&i| i != hub)
+                .map(|i| (hub, i))
+                .collect(),
+            Pairing::Sequential => {
+                // Frames without a date follow the others in the order of their paths.
+                let key = |i: usize| {
+                    let metadata = &view.layers[i].metadata;
+                    (metadata.date.is_none(), &metadata.date, &metadata.path)
+                };
+                let mut order = (0..count).collect::<Vec<usize>>();
+                order.sort_by(|&a, &b| key(a).cmp(&key(b)));
+                order
+                    .iter()
                     .enumerate()
-                    .filter(move |(n2, _)|
-                        n1 < *n2
+                    .flat_map(|(i, &n1)| order
+                        .iter()
+                        .skip(i + 1)
+                        .take(self.neighbours)
+                        .map(move |&n2| (n1, n2))
                     )
-                    .map(move |(n2, l2)| ((n1, l1), (n2, l2)))
-            )
-            .flatten()
-            // Iterates through all possible layer combinations.
+                    .collect()
+            },
+            Pairing::Similar => {
+                println!("Comparing thumbnails of {} images ...", count);
+                let descriptors = view.layers
+                    .par_iter()
+                    .map(|layer| Descriptor::new(&layer.image))
+                    .collect::<Vec<Descriptor>>();
+                (0..count)
+                    .into_par_iter()
+                    .flat_map(|n1| {
+                        let mut nearest = (0..count)
+                            .filter(|&n2| n2 != n1)
+                            .map(|n2| (n2, descriptors[n1].distance(&descriptors[n2])))
+                            .collect::<Vec<(usize, f32)>>();
+                        if self.neighbours < nearest.len() {
+                            nearest.select_nth_unstable_by(self.neighbours, |a, b| a.1.partial_cmp(&b.1).unwrap_or(Ordering::Equal));
+                            nearest.truncate(self.neighbours);
+                        }
+                        nearest
+                            .into_iter()
+                            .map(|(n2, _)| (n1, n2))
+                            .collect::<Vec<(usize, usize)>>()
+                    })
+                    .collect()
+            },
+        };
+
+        for pair in candidates.iter_mut() {
+            *pair = (min(pair.0, pair.1), max(pair.0, pair.1));
+        }
+        candidates.sort();
+        candidates.dedup();
+        candidates
+    }
+
+    // Searches the offset with the highest confidence for every pair of images with the given
+    // search, if enabled also trying the second image rotated by 180 degrees.
+    fn offsets(&self, view: &View, candidates: &[(usize, usize)], search: impl Fn(&Image, &Image) -> (Point, f32) + Sync) -> Vec<Pair> {
+        candidates
+            .par_iter()
+            .map(|&(n1, n2)| ((n1, &view.layers[n1]), (n2, &view.layers[n2])))
+            // Iterates through the candidate layer combinations.
             .map(|((n1, l1), (n2, l2))| {
-                let mut px = -(min(l1.image.width(), l2.image.width()) as i32 / 2);
-                let mut rx = max(l1.image.width(), l2.image.width())  as i32;
-                let mut py = -(min(l1.image.height(), l2.image.height()) as i32 / 2);
-                let mut ry = max(l1.image.height(), l2.image.height())  as i32;
-                let mut result = (Vector::zeros(), 0.0);
-
-                let r =
-                    (min(
-                        min(l1.image.width(), l1.image.height()), 
-                        min(l2.image.width(), l2.image.height())
-                    ) as f32 / 32.0).log2() as u32;
-
-                for g in (0..=r).map(|x| 2_u32.pow(r - x)) {
-
-                    result = (px..(px + rx))
-                        .into_par_iter()
-                        .filter(move |i| i % g as i32 == 0)
-                        .map(move |x| 
-                            (py..(py + ry))
-                                .into_par_iter()
-                                .filter(move |i| i % g as i32 == 0)
-                                .map(move |y| Vector::new(x, y))
-                        )
-                        .flatten()
-                        // Iterates through all possible image positions.
-                        .map(move |i2_rel_to_i1| {
-                            (i2_rel_to_i1, image_difference(
-                                &l1.image, 
-                                &l2.image,
-                                &i2_rel_to_i1,
-                                g
-                            ))
-                        })
-                        .reduce(|| (Vector::zeros(), 1.0), |a, b| {
-                            if a.1 < b.1 {
-                                a
-                            } else {
-                                b
-                            }
-                        });
-
-                    println!("Searched area {} {} {} {}, best position was {} {}.", px, py, px + rx, py + ry, result.0.x, result.0.y);
-
-                    px = result.0.x - g as i32;
-                    rx = g as i32 * 2;
-                    py = result.0.y - g as i32;
-                    ry = g as i32 * 2;
+                let (offset, confidence) = search(&l1.image, &l2.image);
+                let pair = Pair {
+                    first: n1,
+                    second: n2,
+                    transform: Transform::translation(offset.x, offset.y),
+                    confidence,
+                };
+                if !self.flip {
+                    return pair;
+                }
+
+                // Frames taken after a meridian flip are rotated by 180 degrees.
+                let flipped = l2.image.rotate180();
+                let (flipped_offset, flipped_confidence) = search(&l1.image, &flipped);
+
+                if flipped_confidence > confidence {
+                    println!("Frame \"{}\" is rotated by 180 degrees relative to \"{}\".", name(view, n2), name(view, n1));
+                    Pair {
+                        transform: Transform::flip(l2.image.width(), l2.image.height())
+                            .then(&Transform::translation(flipped_offset.x, flipped_offset.y)),
+                        confidence: flipped_confidence,
+                        ..pair
+                    }
+                } else {
+                    pair
                 }
-                (n1, n2, result.0, result.1)
             })
-            .collect::<Vec<(usize, usize, Vector, f32)>>();
+            .collect()
+    }
 
-        // We now perform Kruskal's algorithm to join the images.
-        let mut partitions = PartitionVec::from_iter((0..view.layers.len()).map(|_| Vector::zeros()));
-        matches.par_sort_by(|(_, _, _, e1), (_, _, _, e2)|
-            e1.partial_cmp(e2).unwrap()
+    // Registers each candidate pair of images by the asterisms of their detected stars.
+    // The confidence of a pair is the fraction of the stars in the overlap that were matched.
+    fn stars(&self, view: &View, candidates: &[(usize, usize)], stars: &[Vec<stars::Star>]) -> Vec<Pair> {
+        let matches = candidates
+            .par_iter()
+            .map(|&(n1, n2)| {
+                let size = |i: usize| (view.layers[i].image.width(), view.layers[i].image.height());
+                (n1, n2, asterism::register(&stars[n1], &stars[n2], [size(n1), size(n2)], self.model))
+            })
+            .collect::<Vec<(usize, usize, Option<asterism::Match>)>>();
+
+        // Every frame is reported with the match that shares the most stars.
+        for i in 0..view.layers.len() {
+            let best = matches
+                .iter()
+                .filter(|(n1, n2, _)| *n1 == i || *n2 == i)
+                .filter_map(|(_, _, result)| result.as_ref())
+                .max_by_key(|result| result.matched);
+            match best {
+                Some(best) => println!(
+                    "Frame \"{}\" matched {} stars with a residual of {:.2} pixels.",
+                    name(view, i), best.matched, best.residual
+                ),
+                None => println!("Frame \"{}\" could not be matched to any other frame.", name(view, i))
+            }
+        }
+
+        matches
+            .into_iter()
+            .filter_map(|(n1, n2, result)| match result {
+                Some(result) => {
+                    let transform = result.transform;
+                    let offset = transform.offset();
+                    println!(
+                        "Matched {} of {} stars between \"{}\" and \"{}\", offset {:.2} {:.2}, rotation {:.2}°, scale {:.4}, residual {:.2} pixels.",
+                        result.matched, result.stars, name(view, n1), name(view, n2),
+                        offset.x, offset.y, transform.rotation(), transform.scale(), result.residual
+                    );
+                    if (transform.rotation().abs() - 180.0).abs() < 5.0 {
+                        println!("Frame \"{}\" is rotated by 180 degrees relative to \"{}\".", name(view, n2), name(view, n1));
+                    }
+                    Some(Pair {
+                        first: n1,
+                        second: n2,
+                        transform,
+                        confidence: (result.matched as f32 / result.stars.max(1) as f32).min(1.0),
+                    })
+                },
+                None => {
+                    println!("No matching stars between \"{}\" and \"{}\".", name(view, n1), name(view, n2));
+                    None
+                }
+            })
+            .collect()
+    }
+
+    // Refines the transforms from the tree by a global least squares solve over all pairs
+    // and reports how much each pair disagrees with the result.
+    fn refine(&self, view: &View, transforms: &mut [Transform], fixed: &[bool], pairs: &[Pair]) {
+        let edges = pairs
+            .iter()
+            .map(|pair| adjustment::Edge {
+                first: pair.first,
+                second: pair.second,
+                transform: pair.transform,
+            })
+            .collect::<Vec<adjustment::Edge>>();
+        let sizes = view.layers
+            .iter()
+            .map(|layer| (layer.image.width(), layer.image.height()))
+            .collect::<Vec<(u32, u32)>>();
+        // Searched offsets only determine translations.
+        let model = match self.method {
+            Method::Stars => self.model,
+            _ => Model::Translation,
+        };
+
+        println!("Adjusting {} images over {} pairs ...", view.layers.len(), edges.len());
+        let residuals = adjustment::adjust(transforms, fixed, &edges, &sizes, model);
+
+        let mut report = edges
+            .iter()
+            .zip(residuals)
+            .collect::<Vec<(&adjustment::Edge, adjustment::Residual)>>();
+        report.sort_by(|a, b| b.1.distance.partial_cmp(&a.1.distance).unwrap_or(Ordering::Equal));
+        for (edge, residual) in report {
+            println!(
+                "Pair \"{}\" and \"{}\" has a residual of {:.2} pixels with weight {:.2}{}",
+                name(view, edge.first), name(view, edge.second), residual.distance, residual.weight,
+                if residual.weight < 0.5 { ", the frames disagree." } else { "." }
+            );
+        }
+    }
+}
+
+impl Operation for Position {
+    fn apply(&self, mut view: View) -> error::Result<View> {
+        println!("Finding relative positions of images ...");
+
+        if view.layers.is_empty() {
+            return Ok(view);
+        }
+        let reference = self.reference.find(&view)?;
+
+        if self.method == Method::None {
+            let reference = reference.unwrap_or(0);
+            println!("Keeping all images in place relative to \"{}\".", name(&view, reference));
+            for layer in view.layers.iter_mut() {
+                layer.transform = Transform::identity();
+            }
+            view.reference = Some(reference);
+            return Ok(view);
+        }
+
+        // Frames without a path, for example integrated ones, are never cached.
+        let frames = view.layers
+            .iter()
+            .map(|layer| layer.metadata.path.as_ref().and_then(|path| Frame::new(path)))
+            .collect::<Option<Vec<Frame>>>();
+        if let Some(frames) = &frames {
+            if self.restore(&mut view, frames) {
+                return Ok(view);
+            }
+        }
+        let settings = self.key(&view);
+
+        // Stars are needed to match them and to find the best reference frame.
+        let stars = if self.method == Method::Stars || reference.is_none() {
+            let stars = view.layers
+                .par_iter()
+                .map(|layer| stars::detect(&layer.image, self.sigma))
+                .collect::<Vec<Vec<stars::Star>>>();
+            for (i, stars) in stars.iter().enumerate() {
+                println!("Detected {} stars in \"{}\".", stars.len(), name(&view, i));
+            }
+            stars
+        } else {
+            Vec::new()
+        };
+        // The reference is chosen by the same score as the frames are selected by, only the stars
+        // count for it.
+        let ranking = stars
+            .iter()
+            .map(|stars| Quality::new(stars, 0.0, 0.0).score())
+            .collect::<Vec<f32>>();
+
+        // Without an explicit reference, the frame of the best quality is the hub of reference pairing.
+        let hub = reference.unwrap_or_else(|| (0..view.layers.len())
+            .rev()
+            .max_by(|&a, &b| ranking[a].partial_cmp(&ranking[b]).unwrap_or(Ordering::Equal))
+            .unwrap_or(0)
         );
+        let candidates = self.candidates(&view, hub);
+        println!(
+            "Matching {} of {} pairs ...",
+            candidates.len(), view.layers.len() * (view.layers.len() - 1) / 2
+        );
+
+        // Pairs contains the positions of the images relative to each other.
+        let correlator = phase::Correlator::new();
+        let pairs = match self.method {
+            Method::Difference => self.offsets(&view, &candidates, |i1, i2| search(i1, i2, self.overlap)),
+            Method::Phase => self.offsets(&view, &candidates, |i1, i2| correlator.register(i1, i2)),
+            Method::Stars => self.stars(&view, &candidates, &stars),
+            // The frames were left in place above.
+            Method::None => Vec::new(),
+        };
+
+        // Pairs that barely overlap or could be chance matches are left out.
+        let mut pairs = pairs
+            .into_iter()
+            .filter(|pair| {
+                let size = |i: usize| (view.layers[i].image.width(), view.layers[i].image.height());
+                let overlap = overlap(&pair.transform, size(pair.first), size(pair.second));
+                let accepted = overlap >= self.overlap && pair.confidence >= self.confidence;
+                println!(
+                    "Pair \"{}\" and \"{}\" overlaps by {:.0}% with a confidence of {:.2}{}",
+                    name(&view, pair.first), name(&view, pair.second), overlap * 100.0, pair.confidence,
+                    if accepted { "." } else { ", rejected." }
+                );
+                accepted
+            })
+            .collect::<Vec<Pair>>();
 
-        println!("Matches are: {:?}", matches);
+        // We now perform Kruskal's algorithm to join the images.
+        let mut partitions = PartitionVec::from_iter((0..view.layers.len()).map(|_| Transform::identity()));
+        pairs.par_sort_by(|p1, p2|
+            p2.confidence.partial_cmp(&p1.confidence).unwrap_or(Ordering::Equal)
+        );
 
-        for (i1, i2, i2_rel_to_i1, _) in matches {
+        for pair in &pairs {
+            let (i1, i2) = (pair.first, pair.second);
             if !partitions.same_set(i1, i2) {
-                let move_to = (partitions[i1] + i2_rel_to_i1) - partitions[i2];
-                for (_, position) in partitions.set_mut(i2) {
-                    *position += move_to;
+                // Brings the set of the second image into the coordinates of the first image's set.
+                let move_to = partitions[i2]
+                    .inverse()
+                    .then(&pair.transform)
+                    .then(&partitions[i1]);
+                for (_, transform) in partitions.set_mut(i2) {
+                    *transform = transform.then(&move_to);
                 }
                 partitions.union(i1, i2);
             }
@@ -155,12 +841,164 @@ impl Operation for Position {
             }
         }
 
-        println!("Partitions are: {:?}", partitions);
-        
-        for (i, position) in partitions.into_iter().enumerate() {
-            view.layers[i].position = position;
+        // Only the group of connected images containing the chosen reference or, without one, the
+        // largest group is kept, the others cannot be placed.
+        let count = view.layers.len();
+        let anchor = reference.unwrap_or_else(|| partitions
+            .all_sets()
+            .map(|set| set.map(|(i, _)| i).collect::<Vec<usize>>())
+            // Of equally large groups, the one with the first frame is taken.
+            .max_by_key(|set| (set.len(), std::cmp::Reverse(set.iter().min().cloned())))
+            .and_then(|set| set.into_iter().min())
+            .unwrap_or(0)
+        );
+        let connected = (0..count)
+            .map(|i| partitions.same_set(anchor, i))
+            .collect::<Vec<bool>>();
+        for i in (0..count).filter(|&i| !connected[i]) {
+            println!("Image \"{}\" could not be connected to the other images and is left out.", name(&view, i));
+        }
+
+        let reference = match reference {
+            Some(reference) => {
+                println!("Frame \"{}\" is the reference.", name(&view, reference));
+                reference
+            },
+            None => {
+                let reference = (0..count)
+                    .filter(|&i| connected[i])
+                    // The first of equally good frames is taken.
+                    .rev()
+                    .max_by(|&a, &b| ranking[a].partial_cmp(&ranking[b]).unwrap_or(Ordering::Equal))
+                    .unwrap_or(anchor);
+                let quality = Quality::new(&stars[reference], 0.0, 0.0);
+                println!(
+                    "Frame \"{}\" is the reference with {} stars and a median FWHM of {:.2} pixels.",
+                    name(&view, reference), quality.stars, quality.fwhm
+                );
+                reference
+            }
+        };
+
+        let scores = pairs
+            .iter()
+            .map(|pair| (pair.first, pair.second, pair.confidence))
+            .collect::<Vec<(usize, usize, f32)>>();
+
+        // Indices of the kept images after removing the others.
+        let mut indices = vec![None; count];
+        for (index, i) in (0..count).filter(|&i| connected[i]).enumerate() {
+            indices[i] = Some(index);
+        }
+        let pairs = pairs
+            .into_iter()
+            .filter_map(|pair| match (indices[pair.first], indices[pair.second]) {
+                (Some(first), Some(second)) => Some(Pair {
+                    first,
+                    second,
+                    ..pair
+                }),
+                _ => None
+            })
+            .collect::<Vec<Pair>>();
+
+        // The transforms are expressed relative to the reference instead of the root of the tree.
+        let origin = partitions[reference].inverse();
+        let mut transforms = partitions
+            .into_iter()
+            .zip(connected.iter())
+            .filter(|(_, &connected)| connected)
+            .map(|(transform, _)| transform.then(&origin))
+            .collect::<Vec<Transform>>();
+        view.layers = view.layers
+            .into_iter()
+            .zip(connected.iter())
+            .filter(|(_, &connected)| connected)
+            .map(|(layer, _)| layer)
+            .collect();
+
+        // Only the reference keeps its transform.
+        let fixed = (0..transforms.len())
+            .map(|i| Some(i) == indices[reference])
+            .collect::<Vec<bool>>();
+        view.reference = indices[reference];
+
+        if self.adjust {
+            self.refine(&view, &mut transforms, &fixed, &pairs);
+        }
+
+        if let (Some(path), Some(frames)) = (&self.cache, frames) {
+            let mut kept = transforms.iter();
+            let registration = Registration {
+                settings,
+                frames,
+                transforms: connected
+                    .iter()
+                    .map(|&connected| if connected { kept.next().cloned() } else { None })
+                    .collect(),
+                reference,
+                pairs: scores,
+            };
+            // The results are still valid if they cannot be cached.
+            match registration.write(path) {
+                Ok(()) => println!("Saved registration to \"{}\".", path.display()),
+                Err(error) => println!("Could not save registration: {}", error)
+            }
+        }
+
+        for (layer, transform) in view.layers.iter_mut().zip(transforms) {
+            layer.transform = transform;
         }
 
         Ok(view)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::Layer;
+
+    #[test]
+    fn references_are_parsed_as_index_keyword_or_path() {
+        let directory = std::env::temp_dir().join(format!("strata-reference-test-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        std::fs::write(directory.join("auto"), b"").unwrap();
+        std::fs::write(directory.join("7"), b"").unwrap();
+
+        assert_eq!(Reference::parse("#3", &directory), Reference::Index(3));
+        assert_eq!(Reference::parse("3", &directory), Reference::Index(3));
+        assert_eq!(Reference::parse("auto", Path::new("")), Reference::Automatic);
+        assert_eq!(Reference::parse("light.fits", &directory), Reference::Path(PathBuf::from("light.fits")));
+        // Files of these names in the input directory are not mistaken for the keyword or an index.
+        assert_eq!(Reference::parse("auto", &directory), Reference::Path(PathBuf::from("auto")));
+        assert_eq!(Reference::parse("7", &directory), Reference::Path(PathBuf::from("7")));
+        assert_eq!(Reference::parse("#7", &directory), Reference::Index(7));
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn references_are_found_by_relative_or_absolute_path() {
+        let directory = std::env::temp_dir().join(format!("strata-reference-find-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let layers = ["a.png", "b.png"]
+            .iter()
+            .map(|name| {
+                std::fs::write(directory.join(name), b"").unwrap();
+                let mut layer = Layer::new(Image::new(1, 1, 1));
+                // Frames are loaded with the path of the input directory as given.
+                layer.metadata.path = Some(directory.join(".").join(name));
+                layer
+            })
+            .collect();
+        let view = View { layers, ..View::default() };
+
+        let absolute = directory.canonicalize().unwrap().join("b.png");
+        assert_eq!(Reference::Path(absolute).find(&view).unwrap(), Some(1));
+        assert_eq!(Reference::Path(PathBuf::from("b.png")).find(&view).unwrap(), Some(1));
+        assert!(Reference::Path(directory.join("c.png")).find(&view).is_err());
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -0,0 +1,121 @@
+use super::{
+    Metadata,
+    buffer::Image,
+    cfa::{
+        Color,
+        Pattern,
+    },
+    error::{
+        self,
+        Error,
+    },
+};
+use std::{
+    fs::File,
+    io::BufReader,
+    path::Path,
+};
+use rawloader::RawImageData;
+use exif::{
+    In,
+    Tag,
+};
+
+const EXTENSIONS: [&str; 12] = ["dng", "cr2", "crw", "nef", "nrw", "arw", "srf", "sr2", "orf", "rw2", "pef", "srw"];
+
+// Returns true if the path has an extension of a supported camera raw format.
+pub fn is_raw(path: &Path) -> bool {
+    path.extension()
+        .map(|extension| EXTENSIONS.contains(&extension.to_string_lossy().to_lowercase().as_str()))
+        .unwrap_or(false)
+}
+
+// The undemosaiced sensor data of a camera raw file.
+pub struct Raw {
+    // The mosaic as mono image, with the black level subtracted and divided by the range up to the
+    // white level.
+    pub image: Image,
+    pub pattern: Pattern,
+    pub metadata: Metadata,
+}
+
+impl Raw {
+    pub fn read(path: &Path) -> error::Result<Raw> {
+        let raw = rawloader::decode_file(path)
+            .map_err(|error| Error::image(format!("Invalid raw file, {}.", error)))?;
+
+        if raw.cpp != 1 {
+            return Err(Error::image("Raw file is already demosaiced.".to_string()));
+        }
+
+        // The crops are given as top, right, bottom and left.
+        let (top, right, bottom, left) = (raw.crops[0], raw.crops[1], raw.crops[2], raw.crops[3]);
+        let width = raw.width - left - right;
+        let height = raw.height - top - bottom;
+
+        let color = |x: usize, y: usize| match raw.cfa.color_at(top + y, left + x) {
+            0 => Some(Color::Red),
+            1 => Some(Color::Green),
+            2 => Some(Color::Blue),
+            _ => None
+        };
+        let pattern = match (color(0, 0), color(1, 0), color(0, 1), color(1, 1)) {
+            (Some(a), Some(b), Some(c), Some(d)) => Pattern::from_colors([a, b, c, d]),
+            _ => None
+        }.ok_or_else(|| Error::image(format!("Unsupported color filter array \"{}\".", raw.cfa.name)))?;
+
+        // Black and white levels are given per color of the filter array.
+        let levels = |x: usize, y: usize| {
+            let c = raw.cfa.color_at(top + y, left + x).min(3);
+            let black = raw.blacklevels[c] as f32;
+            (black, (raw.whitelevels[c] as f32 - black).max(1.0))
+        };
+        let levels = [levels(0, 0), levels(1, 0), levels(0, 1), levels(1, 1)];
+
+        let mut image = Image::new(width as u32, height as u32, 1);
+        for (i, value) in image.as_raw_mut().iter_mut().enumerate() {
+            let (x, y) = (i % width, i / width);
+            let j = (top + y) * raw.width + left + x;
+            let (black, range) = levels[(y % 2) * 2 + x % 2];
+            // Values below the black level are kept, so that the noise of dark frames stays unbiased.
+            *value = match &raw.data {
+                RawImageData::Integer(data) => (data[j] as f32 - black) / range,
+                RawImageData::Float(data) => (data[j] - black) / range,
+            };
+        }
+
+        let mut metadata = read_exif(path);
+        metadata.keywords.insert("MAKE".to_string(), raw.clean_make.clone());
+        metadata.keywords.insert("MODEL".to_string(), raw.clean_model.clone());
+
+        Ok(Raw {
+            image,
+            pattern,
+            metadata,
+        })
+    }
+}
+
+// Reads exposure information from the EXIF data of a file, missing values are left empty.
+pub fn read_exif(path: &Path) -> Metadata {
+    let mut metadata = Metadata::default();
+
+    let exif = File::open(path)
+        .ok()
+        .and_then(|file| exif::Reader::new().read_from_container(&mut BufReader::new(file)).ok());
+
+    if let Some(exif) = exif {
+        metadata.exposure = exif.get_field(Tag::ExposureTime, In::PRIMARY)
+            .and_then(|field| match &field.value {
+                exif::Value::Rational(values) => values.first().map(|value| value.to_f64()),
+                _ => None
+            });
+        metadata.iso = exif.get_field(Tag::PhotographicSensitivity, In::PRIMARY)
+            .and_then(|field| field.value.get_uint(0))
+            .map(|iso| iso as f64);
+        metadata.date = exif.get_field(Tag::DateTimeOriginal, In::PRIMARY)
+            .map(|field| field.display_value().to_string());
+    }
+
+    metadata
+}
@@ -0,0 +1,163 @@
+use super::buffer::Image;
+use std::f32::consts::PI;
+
+// Interpolation kernels used to read images at fractional coordinates.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kernel {
+    // Takes the closest pixel, which keeps the noise but misaligns by up to half a pixel.
+    Nearest,
+    // Weights the 2x2 surrounding pixels linearly, which smooths slightly.
+    Bilinear,
+    // Catmull-Rom spline through the 4x4 surrounding pixels.
+    Bicubic,
+    // Windowed sinc over the 6x6 surrounding pixels, the sharpest but may ring around bright stars.
+    Lanczos3,
+}
+
+impl Kernel {
+    pub fn parse(name: &str) -> Option<Kernel> {
+        match name.to_lowercase().as_str() {
+            "nearest" => Some(Kernel::Nearest),
+            "bilinear" => Some(Kernel::Bilinear),
+            "bicubic" => Some(Kernel::Bicubic),
+            "lanczos3" => Some(Kernel::Lanczos3),
+            _ => None
+        }
+    }
+
+    // Number of pixels on each side of the sampled position that contribute.
+    fn radius(&self) -> i32 {
+        match self {
+            Kernel::Nearest => 0,
+            Kernel::Bilinear => 1,
+            Kernel::Bicubic => 2,
+            Kernel::Lanczos3 => 3,
+        }
+    }
+
+    fn weight(&self, distance: f32) -> f32 {
+        let distance = distance.abs();
+        match self {
+            Kernel::Nearest => if distance <= 0.5 { 1.0 } else { 0.0 },
+            Kernel::Bilinear => (1.0 - distance).max(0.0),
+            Kernel::Bicubic => if distance < 1.0 {
+                1.5 * distance.powi(3) - 2.5 * distance.powi(2) + 1.0
+            } else if distance < 2.0 {
+                -0.5 * distance.powi(3) + 2.5 * distance.powi(2) - 4.0 * distance + 2.0
+            } else {
+                0.0
+            },
+            Kernel::Lanczos3 => if distance < 1e-6 {
+                1.0
+            } else if distance < 3.0 {
+                let x = PI * distance;
+                3.0 * x.sin() * (x / 3.0).sin() / (x * x)
+            } else {
+                0.0
+            },
+        }
+    }
+}
+
+impl Default for Kernel {
+    fn default() -> Kernel {
+        Kernel::Lanczos3
+    }
+}
+
+// Interpolates the image at the given position, where integer coordinates are pixel centers.
+// Pixels beyond the borders repeat the border. Returns none outside of the image.
+pub fn sample(image: &Image, x: f32, y: f32, kernel: Kernel) -> Option<Vec<f32>> {
+    let (width, height) = (image.width() as i32, image.height() as i32);
+    if x < -0.5 || y < -0.5 || x >= width as f32 - 0.5 || y >= height as f32 - 0.5 {
+        return None;
+    }
+
+    if kernel == Kernel::Nearest {
+        return Some(image.get_pixel(x.round() as u32, y.round() as u32).to_vec());
+    }
+
+    let radius = kernel.radius();
+    let (fx, fy) = (x.floor() as i32, y.floor() as i32);
+    let mut result = vec![0.0; image.channels()];
+    let mut sum = 0.0;
+
+    for py in (fy - radius + 1)..=(fy + radius) {
+        let wy = kernel.weight(y - py as f32);
+        if wy == 0.0 {
+            continue;
+        }
+        for px in (fx - radius + 1)..=(fx + radius) {
+            let weight = wy * kernel.weight(x - px as f32);
+            if weight == 0.0 {
+                continue;
+            }
+            let pixel = image.get_pixel(px.max(0).min(width - 1) as u32, py.max(0).min(height - 1) as u32);
+            for (value, channel) in result.iter_mut().zip(pixel) {
+                *value += weight * channel;
+            }
+            sum += weight;
+        }
+    }
+
+    // The weights of the kernels do not add up to exactly one at every position.
+    if sum != 0.0 {
+        for value in result.iter_mut() {
+            *value /= sum;
+        }
+    }
+
+    Some(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KERNELS: [Kernel; 4] = [Kernel::Nearest, Kernel::Bilinear, Kernel::Bicubic, Kernel::Lanczos3];
+
+    // Values that differ at every pixel.
+    fn image() -> Image {
+        Image::from_raw(8, 6, 2, (0..96).map(|i| ((i * 37) % 17) as f32 / 17.0).collect())
+    }
+
+    #[test]
+    fn weights_add_up_to_one() {
+        for &kernel in &[Kernel::Bilinear, Kernel::Bicubic, Kernel::Lanczos3] {
+            // Lanczos weights are only close to one and are normalized when sampling.
+            let tolerance = if kernel == Kernel::Lanczos3 { 0.02 } else { 1e-5 };
+            for step in 0..10 {
+                let offset = step as f32 / 10.0;
+                let sum = (-kernel.radius()..=kernel.radius())
+                    .map(|i| kernel.weight(offset - i as f32))
+                    .sum::<f32>();
+                assert!((sum - 1.0).abs() < tolerance, "{:?} weights add up to {} at {}", kernel, sum, offset);
+            }
+        }
+    }
+
+    #[test]
+    fn pixel_centers_are_sampled_exactly() {
+        let image = image();
+        for &kernel in &KERNELS {
+            for y in 0..6 {
+                for x in 0..8 {
+                    let sample = sample(&image, x as f32, y as f32, kernel).unwrap();
+                    for (value, expected) in sample.iter().zip(image.get_pixel(x, y)) {
+                        assert!((value - expected).abs() < 1e-6, "{:?} at {} {}", kernel, x, y);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn constant_images_stay_constant() {
+        let image = Image::from_raw(5, 5, 1, vec![0.3; 25]);
+        for &kernel in &KERNELS {
+            let value = sample(&image, 2.3, 1.7, kernel).unwrap()[0];
+            assert!((value - 0.3).abs() < 1e-6, "{:?} gives {}", kernel, value);
+        }
+        assert!(sample(&image, -0.6, 2.0, Kernel::Bilinear).is_none());
+    }
+}
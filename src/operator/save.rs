@@ -1,20 +1,97 @@
 use super::{
     Operation,
     View,
+    Layer,
+    buffer::Image,
+    fits::{
+        self,
+        Card,
+        Depth,
+    },
     error,
 };
-use std::path::PathBuf;
+use std::path::{
+    Path,
+    PathBuf,
+};
+
+// Returns true if the format of the path can store 16 bits per channel.
+fn supports_16_bit(path: &Path) -> bool {
+    path.extension()
+        .map(|extension| {
+            let extension = extension.to_string_lossy().to_lowercase();
+            extension == "png" || extension == "tif" || extension == "tiff"
+        })
+        .unwrap_or(false)
+}
 
 pub struct Save {
-    path: PathBuf
+    path: PathBuf,
+    depth: Depth
 }
 
 impl Save {
     pub fn new(path: PathBuf) -> Save {
         Save {
-            path
+            path,
+            depth: Depth::default()
         }
     }
+
+    // Sets the data type used when saving to a FITS file.
+    pub fn depth(mut self, depth: Depth) -> Save {
+        self.depth = depth;
+        self
+    }
+
+    // Path of the weight map next to the result.
+    fn weights_path(&self) -> PathBuf {
+        let stem = self.path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        self.path.with_file_name(format!("{}.weights.fits", stem))
+    }
+
+    fn save_fits(&self, path: &Path, image: &Image, depth: Depth, layer: &Layer, history: &[String]) -> error::Result<()> {
+        let (width, height) = (image.width() as usize, image.height() as usize);
+        let area = width * height;
+
+        // FITS stores the channels as separate planes.
+        let mut data = vec![0.0; area * image.channels()];
+        for (x, y, pixel) in image.enumerate_pixels() {
+            for (c, value) in pixel.iter().enumerate() {
+                data[c * area + y as usize * width + x as usize] = *value;
+            }
+        }
+
+        // Provenance of the result, so that it can be used for further analysis.
+        let metadata = &layer.metadata;
+        let mut cards = vec![
+            Card::Integer("NCOMBINE", metadata.frames as i64),
+        ];
+        if let Some(exposure) = metadata.exposure {
+            cards.push(Card::Float("EXPTIME", exposure));
+        }
+        if let Some(iso) = metadata.iso {
+            cards.push(Card::Float("ISOSPEED", iso));
+        }
+        if let Some(gain) = metadata.gain {
+            cards.push(Card::Float("GAIN", gain));
+        }
+        if let Some(filter) = &metadata.filter {
+            cards.push(Card::String("FILTER", filter.clone()));
+        }
+        if let Some(reference) = metadata.reference.as_ref().and_then(|path| path.file_name()) {
+            cards.push(Card::String("REFFRAME", reference.to_string_lossy().into_owned()));
+        }
+        cards.push(Card::String("CREATOR", format!("Strata {}", env!("CARGO_PKG_VERSION"))));
+        for step in history {
+            cards.push(Card::History(format!("Strata: {}", step)));
+        }
+
+        fits::write(path, width, height, image.channels(), &data, depth, &cards)
+    }
 }
 
 impl Operation for Save {
@@ -23,10 +100,24 @@ impl Operation for Save {
 
         debug_assert_eq!(view.layers.len(), 1);
 
-        view.layers[0].image.save(&self.path)?;
+        let image = &view.layers[0].image;
+        if fits::is_fits(&self.path) {
+            self.save_fits(&self.path, image, self.depth, &view.layers[0], &view.history)?;
+        } else if supports_16_bit(&self.path) {
+            image.to_dynamic16().save(&self.path)?;
+        } else {
+            image.to_dynamic8().save(&self.path)?;
+        }
+
+        // The weight map is always stored as a floating point FITS file, as the weights exceed one.
+        if let Some(weights) = &view.layers[0].weights {
+            let path = self.weights_path();
+            println!("Saving weight map to \"{}\" ...", path.display());
+            self.save_fits(&path, weights, Depth::Float, &view.layers[0], &view.history)?;
+        }
 
         println!("Result saved, goodbye!");
 
         Ok(view)
     }
-}
\ No newline at end of file
+}
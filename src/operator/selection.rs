@@ -0,0 +1,266 @@
+use super::{
+    Operation,
+    View,
+    buffer::Image,
+    position::name,
+    stars,
+    statistics,
+    error::{
+        self,
+        Error,
+    },
+};
+use std::cmp::Ordering;
+use rayon::prelude::*;
+
+// Detection threshold for stars in standard deviations of the background noise.
+const SIGMA: f32 = 5.0;
+
+// Measures of the quality of a frame, which reveal clouds, wind shake or drifting focus.
+#[derive(Clone, Copy, Debug)]
+pub struct Quality {
+    pub stars: usize,
+    // Median full width at half maximum of the stars in pixels, infinite without stars.
+    pub fwhm: f32,
+    // Median eccentricity of the stars, 0 for round stars and infinite without stars.
+    pub eccentricity: f32,
+    // Median level of the luminance, and the noise around the background map.
+    pub background: f32,
+    pub noise: f32,
+    // Median ratio of the peaks of the stars above the background to the noise, 0 without stars.
+    pub snr: f32,
+}
+
+impl Quality {
+    // The quality of a frame from its detected stars and the level and noise of its background.
+    pub fn new(stars: &[stars::Star], background: f32, noise: f32) -> Quality {
+        // Frames without stars fail all thresholds on the stars.
+        let median = |value: fn(&stars::Star) -> f32, otherwise: f32| if stars.is_empty() {
+            otherwise
+        } else {
+            statistics::median(&mut stars.iter().map(value).collect::<Vec<f32>>())
+        };
+
+        Quality {
+            stars: stars.len(),
+            fwhm: median(|star| star.fwhm, f32::INFINITY),
+            eccentricity: median(|star| star.eccentricity, f32::INFINITY),
+            background,
+            noise,
+            snr: if noise > 0.0 { median(|star| star.peak, 0.0) / noise } else { 0.0 },
+        }
+    }
+
+    pub fn measure(image: &Image) -> Quality {
+        let luminance = stars::luminance(image);
+        let (background, _) = stars::background(&luminance);
+        // The noise is measured around the background map like the peaks of the stars, so that
+        // gradients do not count as noise.
+        let (_, noise) = stars::background(&stars::flatten(&luminance, image.width() as usize, image.height() as usize));
+        Quality::new(&stars::detect(image, SIGMA), background, noise)
+    }
+
+    // Frames with more and smaller stars were taken under better seeing and transparency, the
+    // frame with the highest score is the best.
+    pub fn score(&self) -> f32 {
+        self.stars as f32 / self.fwhm
+    }
+}
+
+// Drops frames whose quality does not meet the thresholds and keeps only the given percentage of
+// the best frames, so that bad frames do not degrade the joined image.
+pub struct Selection {
+    min_stars: Option<usize>,
+    max_fwhm: Option<f32>,
+    max_eccentricity: Option<f32>,
+    max_background: Option<f32>,
+    max_noise: Option<f32>,
+    min_snr: Option<f32>,
+    // Percentage of all frames that is kept, ranked by their score.
+    best: Option<f32>,
+}
+
+impl Selection {
+    pub fn new() -> Selection {
+        Selection {
+            min_stars: None,
+            max_fwhm: None,
+            max_eccentricity: None,
+            max_background: None,
+            max_noise: None,
+            min_snr: None,
+            best: None,
+        }
+    }
+
+    // Sets the minimum number of stars, which drops frames taken through clouds.
+    pub fn min_stars(mut self, min_stars: Option<usize>) -> Selection {
+        self.min_stars = min_stars;
+        self
+    }
+
+    // Sets the maximum median FWHM in pixels, which drops blurred or defocused frames.
+    pub fn max_fwhm(mut self, max_fwhm: Option<f32>) -> Selection {
+        self.max_fwhm = max_fwhm;
+        self
+    }
+
+    // Sets the maximum median eccentricity, which drops frames with stars trailed by wind or bad
+    // tracking.
+    pub fn max_eccentricity(mut self, max_eccentricity: Option<f32>) -> Selection {
+        self.max_eccentricity = max_eccentricity;
+        self
+    }
+
+    // Sets the maximum background level, which drops frames brightened by the moon, dawn or
+    // passing lights.
+    pub fn max_background(mut self, max_background: Option<f32>) -> Selection {
+        self.max_background = max_background;
+        self
+    }
+
+    pub fn max_noise(mut self, max_noise: Option<f32>) -> Selection {
+        self.max_noise = max_noise;
+        self
+    }
+
+    pub fn min_snr(mut self, min_snr: Option<f32>) -> Selection {
+        self.min_snr = min_snr;
+        self
+    }
+
+    // Sets the percentage of all frames that is kept, the frames with the best score among those
+    // that meet the thresholds.
+    pub fn best(mut self, best: Option<f32>) -> Selection {
+        self.best = best;
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.min_stars.is_none()
+            && self.max_fwhm.is_none()
+            && self.max_eccentricity.is_none()
+            && self.max_background.is_none()
+            && self.max_noise.is_none()
+            && self.min_snr.is_none()
+            && self.best.is_none()
+    }
+
+    // Describes the thresholds the quality does not meet, none if the frame is good.
+    fn reasons(&self, quality: &Quality) -> Vec<String> {
+        let mut reasons = Vec::new();
+        if let Some(min_stars) = self.min_stars.filter(|&min_stars| quality.stars < min_stars) {
+            reasons.push(format!("{} stars below {}", quality.stars, min_stars));
+        }
+        if let Some(max_fwhm) = self.max_fwhm.filter(|&max_fwhm| quality.fwhm > max_fwhm) {
+            reasons.push(format!("FWHM {:.2} above {}", quality.fwhm, max_fwhm));
+        }
+        if let Some(max_eccentricity) = self.max_eccentricity.filter(|&max_eccentricity| quality.eccentricity > max_eccentricity) {
+            reasons.push(format!("eccentricity {:.2} above {}", quality.eccentricity, max_eccentricity));
+        }
+        if let Some(max_background) = self.max_background.filter(|&max_background| quality.background > max_background) {
+            reasons.push(format!("background {:.4} above {}", quality.background, max_background));
+        }
+        if let Some(max_noise) = self.max_noise.filter(|&max_noise| quality.noise > max_noise) {
+            reasons.push(format!("noise {:.4} above {}", quality.noise, max_noise));
+        }
+        if let Some(min_snr) = self.min_snr.filter(|&min_snr| quality.snr < min_snr) {
+            reasons.push(format!("SNR {:.1} below {}", quality.snr, min_snr));
+        }
+        reasons
+    }
+}
+
+impl Operation for Selection {
+    fn apply(&self, view: View) -> error::Result<View> {
+        println!("Measuring the quality of {} frames ...", view.layers.len());
+
+        let qualities = view.layers
+            .par_iter()
+            .map(|layer| Quality::measure(&layer.image))
+            .collect::<Vec<Quality>>();
+        for (i, quality) in qualities.iter().enumerate() {
+            println!(
+                "Frame \"{}\" has {} stars with a FWHM of {:.2} and an eccentricity of {:.2}, a background of {:.4} with a noise of {:.4} and a SNR of {:.1}.",
+                name(&view, i), quality.stars, quality.fwhm, quality.eccentricity, quality.background, quality.noise, quality.snr
+            );
+        }
+
+        let mut reasons = qualities
+            .iter()
+            .map(|quality| self.reasons(quality))
+            .collect::<Vec<Vec<String>>>();
+
+        if let Some(best) = self.best {
+            let count = ((view.layers.len() as f32 * best / 100.0).ceil() as usize).max(1);
+            let mut ranked = (0..view.layers.len())
+                .filter(|&i| reasons[i].is_empty())
+                .collect::<Vec<usize>>();
+            // Sorting is stable, so frames of equal score are kept in the order they were loaded.
+            ranked.sort_by(|&a, &b| qualities[b].score().partial_cmp(&qualities[a].score()).unwrap_or(Ordering::Equal));
+            for &i in ranked.iter().skip(count) {
+                reasons[i].push(format!("score {:.1} not among the best {}%", qualities[i].score(), best));
+            }
+        }
+
+        for (i, reasons) in reasons.iter().enumerate().filter(|(_, reasons)| !reasons.is_empty()) {
+            println!("Rejecting frame \"{}\": {}.", name(&view, i), reasons.join(", "));
+        }
+
+        let selected = (0..view.layers.len())
+            .filter(|&i| reasons[i].is_empty())
+            .collect::<Vec<usize>>();
+        if selected.is_empty() {
+            return Err(Error::input("All frames were rejected by the quality selection.".to_string()));
+        }
+        println!("Selected {} of {} frames.", selected.len(), view.layers.len());
+
+        let reference = view.reference.and_then(|reference| selected.iter().position(|&i| i == reference));
+        let layers = view.layers
+            .into_iter()
+            .enumerate()
+            .filter(|(i, _)| reasons[*i].is_empty())
+            .map(|(_, layer)| layer)
+            .collect();
+
+        Ok(View {
+            layers,
+            reference,
+            ..
+            view
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A gradient of the given slope across the image from 0.1 with gaussian noise from a fixed seed.
+    fn gradient(size: u32, slope: f32, noise: f32) -> Image {
+        let data = statistics::normal((size * size) as usize)
+            .iter()
+            .enumerate()
+            .map(|(i, normal)| 0.1 + slope * (i as u32 % size) as f32 / size as f32 + noise * normal)
+            .collect();
+        Image::from_raw(size, size, 1, data)
+    }
+
+    #[test]
+    fn gradients_do_not_count_as_noise() {
+        let quality = Quality::measure(&gradient(256, 0.4, 0.002));
+        assert!((quality.noise - 0.002).abs() < 0.0003, "noise {}", quality.noise);
+    }
+
+    #[test]
+    fn frames_without_stars_fail_the_star_thresholds() {
+        let quality = Quality::measure(&gradient(128, 0.0, 0.002));
+        assert_eq!(quality.stars, 0);
+
+        let selection = Selection::new()
+            .max_fwhm(Some(4.0))
+            .max_eccentricity(Some(0.5))
+            .min_snr(Some(10.0));
+        assert_eq!(selection.reasons(&quality).len(), 3);
+    }
+}
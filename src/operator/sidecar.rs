@@ -0,0 +1,236 @@
+use super::{
+    transform::Transform,
+    error,
+};
+use std::{
+    fs,
+    path::{
+        Path,
+        PathBuf,
+    },
+    time::UNIX_EPOCH,
+};
+use nalgebra::Matrix3;
+
+// First line of a sidecar file, changed whenever the format changes.
+const VERSION: &str = "strata-registration 2";
+
+// Paths are written byte by byte, so that they are read back exactly even if they are not valid
+// Unicode. Bytes other than printable ASCII and the percent sign itself are written as "%XX".
+fn encode(path: &Path) -> String {
+    bytes(path)
+        .iter()
+        .map(|&byte| if (byte.is_ascii_graphic() || byte == b' ') && byte != b'%' {
+            (byte as char).to_string()
+        } else {
+            format!("%{:02X}", byte)
+        })
+        .collect()
+}
+
+fn decode(text: &str) -> Option<PathBuf> {
+    let mut bytes = Vec::with_capacity(text.len());
+    let mut rest = text.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        if byte == b'%' {
+            let hex = std::str::from_utf8(tail.get(..2)?).ok()?;
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+            rest = &tail[2..];
+        } else {
+            bytes.push(byte);
+            rest = tail;
+        }
+    }
+    path(bytes)
+}
+
+#[cfg(unix)]
+fn bytes(path: &Path) -> Vec<u8> {
+    use std::os::unix::ffi::OsStrExt;
+    path.as_os_str().as_bytes().to_vec()
+}
+
+#[cfg(unix)]
+fn path(bytes: Vec<u8>) -> Option<PathBuf> {
+    use std::os::unix::ffi::OsStringExt;
+    Some(PathBuf::from(std::ffi::OsString::from_vec(bytes)))
+}
+
+// Elsewhere paths that are not valid Unicode are not matched.
+#[cfg(not(unix))]
+fn bytes(path: &Path) -> Vec<u8> {
+    path.to_string_lossy().into_owned().into_bytes()
+}
+
+#[cfg(not(unix))]
+fn path(bytes: Vec<u8>) -> Option<PathBuf> {
+    String::from_utf8(bytes).ok().map(PathBuf::from)
+}
+
+// Identifies an input frame by its path, size and modification time.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Frame {
+    pub path: PathBuf,
+    pub size: u64,
+    pub modified: u128,
+}
+
+impl Frame {
+    pub fn new(path: &Path) -> Option<Frame> {
+        let metadata = fs::metadata(path).ok()?;
+        let modified = metadata.modified()
+            .ok()?
+            .duration_since(UNIX_EPOCH)
+            .ok()?
+            .as_nanos();
+
+        Some(Frame {
+            path: path.to_path_buf(),
+            size: metadata.len(),
+            modified,
+        })
+    }
+}
+
+// The result of registering a set of frames, which is valid as long as neither the frames nor
+// the settings of the registration change.
+#[derive(Clone, Debug)]
+pub struct Registration {
+    // Describes all settings that influence the result.
+    pub settings: String,
+    pub frames: Vec<Frame>,
+    // The transform of each frame, none for frames that could not be connected.
+    pub transforms: Vec<Option<Transform>>,
+    // Index of the reference frame.
+    pub reference: usize,
+    // The accepted pairs of frames and the confidence of their match.
+    pub pairs: Vec<(usize, usize, f32)>,
+}
+
+impl Registration {
+    // Reads a sidecar file, returns none if it is missing or cannot be parsed.
+    pub fn read(path: &Path) -> Option<Registration> {
+        let content = fs::read_to_string(path).ok()?;
+        let mut lines = content.lines();
+        if lines.next()? != VERSION {
+            return None;
+        }
+
+        let mut registration = Registration {
+            settings: String::new(),
+            frames: Vec::new(),
+            transforms: Vec::new(),
+            reference: 0,
+            pairs: Vec::new(),
+        };
+
+        // Fields are separated by tabs, which are encoded in the paths of frames.
+        for line in lines {
+            let fields = line.split('\t').collect::<Vec<&str>>();
+            match fields.as_slice() {
+                ["settings", settings] => registration.settings = settings.to_string(),
+                ["frame", path, size, modified, transform @ ..] => {
+                    registration.frames.push(Frame {
+                        path: decode(path)?,
+                        size: size.parse().ok()?,
+                        modified: modified.parse().ok()?,
+                    });
+                    registration.transforms.push(match transform {
+                        [] => None,
+                        values => {
+                            let values = values
+                                .iter()
+                                .map(|value| value.parse().ok())
+                                .collect::<Option<Vec<f32>>>()?;
+                            if values.len() != 9 {
+                                return None;
+                            }
+                            Some(Transform::from_matrix(Matrix3::from_row_slice(&values))?)
+                        }
+                    });
+                },
+                ["reference", reference] => registration.reference = reference.parse().ok()?,
+                ["pair", first, second, confidence] => registration.pairs.push((
+                    first.parse().ok()?,
+                    second.parse().ok()?,
+                    confidence.parse().ok()?,
+                )),
+                _ => return None
+            }
+        }
+
+        if registration.reference >= registration.frames.len() {
+            return None;
+        }
+
+        Some(registration)
+    }
+
+    pub fn write(&self, path: &Path) -> error::Result<()> {
+        let mut lines = vec![
+            VERSION.to_string(),
+            format!("settings\t{}", self.settings),
+        ];
+        for (frame, transform) in self.frames.iter().zip(&self.transforms) {
+            let mut line = format!("frame\t{}\t{}\t{}", encode(&frame.path), frame.size, frame.modified);
+            if let Some(transform) = transform {
+                for value in transform.matrix().transpose().iter() {
+                    line.push_str(&format!("\t{}", value));
+                }
+            }
+            lines.push(line);
+        }
+        lines.push(format!("reference\t{}", self.reference));
+        for (first, second, confidence) in &self.pairs {
+            lines.push(format!("pair\t{}\t{}\t{}", first, second, confidence));
+        }
+
+        fs::write(path, lines.join("\n") + "\n").map_err(|error| error::Error::from(error).file(path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn registrations_are_written_and_read() {
+        let mut paths = vec![
+            PathBuf::from("/data/M 31/light_001.fits"),
+            PathBuf::from("/data/100%\tcrop/Ärger.png"),
+        ];
+        #[cfg(unix)]
+        paths.push(path(vec![b'/', b'x', 0xff, 0xfe, b'.', b'c', b'r', b'2']).unwrap());
+
+        let registration = Registration {
+            settings: "Stars Similarity 5".to_string(),
+            frames: paths
+                .iter()
+                .map(|path| Frame {
+                    path: path.clone(),
+                    size: 1234,
+                    modified: 1_600_000_000_123_456_789,
+                })
+                .collect(),
+            transforms: paths
+                .iter()
+                .enumerate()
+                .map(|(i, _)| if i == 1 { None } else { Some(Transform::similarity(1.0, 0.1, i as f32, -2.5)) })
+                .collect(),
+            reference: 0,
+            pairs: vec![(0, 2, 0.75)],
+        };
+
+        let file = std::env::temp_dir().join(format!("strata-sidecar-test-{}", std::process::id()));
+        registration.write(&file).unwrap();
+        let read = Registration::read(&file).unwrap();
+        fs::remove_file(&file).unwrap();
+
+        assert_eq!(read.settings, registration.settings);
+        assert_eq!(read.frames, registration.frames);
+        assert_eq!(read.transforms.len(), registration.transforms.len());
+        assert!(read.transforms[1].is_none());
+        assert_eq!(read.transforms[0].map(|transform| *transform.matrix()), registration.transforms[0].map(|transform| *transform.matrix()));
+        assert_eq!(read.pairs, registration.pairs);
+    }
+}
@@ -0,0 +1,288 @@
+use super::{
+    buffer::Image,
+    statistics,
+};
+use std::cmp::Ordering;
+use rayon::prelude::*;
+
+// Half the size of the square around a peak that is measured.
+const RADIUS: i32 = 4;
+// Width of the gaussian window that the shapes of the stars are measured in, in FWHM of the stars
+// as measured in the square, and its largest half size in pixels.
+const WINDOW: f32 = 2.0;
+const MAXIMUM_RADIUS: i32 = 32;
+// Size of the tiles of the background map.
+const TILE: usize = 32;
+
+// A star found in an image, measured on the luminance above the local background.
+#[derive(Clone, Copy, Debug)]
+pub struct Star {
+    // Intensity weighted centroid in pixels.
+    pub x: f32,
+    pub y: f32,
+    // Summed intensity above the background.
+    pub flux: f32,
+    pub peak: f32,
+    // Full width at half maximum in pixels, assuming a gaussian profile.
+    pub fwhm: f32,
+    // 0 for round stars, approaching 1 for elongated ones.
+    pub eccentricity: f32,
+}
+
+// Averages the channels of an image into one luminance value per pixel.
+pub fn luminance(image: &Image) -> Vec<f32> {
+    let channels = image.channels();
+    image.as_raw()
+        .par_chunks(channels)
+        .map(|pixel| pixel.iter().sum::<f32>() / channels as f32)
+        .collect()
+}
+
+// Estimates the background level and its noise robustly from a subsample of the values.
+pub fn background(values: &[f32]) -> (f32, f32) {
+    let step = (values.len() / 100_000).max(1);
+    let mut sample = values
+        .iter()
+        .step_by(step)
+        .cloned()
+        .collect::<Vec<f32>>();
+    let median = statistics::median(&mut sample);
+    let noise = statistics::mad(&sample, median);
+
+    (median, noise)
+}
+
+// Estimates a smoothly varying background by the medians of tiles, interpolated bilinearly between
+// the tile centers. This removes gradients from light pollution or the moon.
+pub fn background_map(values: &[f32], width: usize, height: usize) -> Vec<f32> {
+    let (columns, rows) = ((width + TILE - 1) / TILE, (height + TILE - 1) / TILE);
+    let medians = (0..(columns * rows))
+        .into_par_iter()
+        .map(|tile| {
+            let (tx, ty) = (tile % columns, tile / columns);
+            let mut tile = (ty * TILE..((ty + 1) * TILE).min(height))
+                .flat_map(|y| (tx * TILE..((tx + 1) * TILE).min(width)).map(move |x| y * width + x))
+                .map(|i| values[i])
+                .collect::<Vec<f32>>();
+            statistics::median(&mut tile)
+        })
+        .collect::<Vec<f32>>();
+
+    // Position between the tile centers and the weight of the following center.
+    let locate = |position: usize, tiles: usize| {
+        let center = (position as f32 + 0.5) / TILE as f32 - 0.5;
+        let index = (center.floor().max(0.0) as usize).min(tiles - 1);
+        let next = (index + 1).min(tiles - 1);
+        (index, next, (center - index as f32).max(0.0).min(1.0))
+    };
+
+    (0..(width * height))
+        .into_par_iter()
+        .map(|i| {
+            let (x0, x1, fx) = locate(i % width, columns);
+            let (y0, y1, fy) = locate(i / width, rows);
+            let top = medians[y0 * columns + x0] * (1.0 - fx) + medians[y0 * columns + x1] * fx;
+            let bottom = medians[y1 * columns + x0] * (1.0 - fx) + medians[y1 * columns + x1] * fx;
+            top * (1.0 - fy) + bottom * fy
+        })
+        .collect()
+}
+
+// Subtracts the background map from the values, which leaves the stars above a flat background
+// of zero.
+pub fn flatten(values: &[f32], width: usize, height: usize) -> Vec<f32> {
+    let map = background_map(values, width, height);
+    values
+        .par_iter()
+        .zip(map)
+        .map(|(value, background)| value - background)
+        .collect()
+}
+
+// FWHM and eccentricity of a star with the given second moments.
+fn shape(xx: f32, yy: f32, xy: f32) -> (f32, f32) {
+    let root = ((xx - yy).powi(2) / 4.0 + xy * xy).sqrt();
+    let (major, minor) = ((xx + yy) / 2.0 + root, ((xx + yy) / 2.0 - root).max(0.0));
+
+    (
+        2.3548 * ((major + minor) / 2.0).sqrt(),
+        if major > 0.0 { (1.0 - minor / major).sqrt() } else { 0.0 },
+    )
+}
+
+// Measures the shape of a star by its second moments within a gaussian window of the given standard
+// deviation, which neither cuts off wide stars nor adds up the noise around small ones. The moments
+// of a gaussian star seen through a gaussian window are those of both gaussians multiplied, which
+// the window is divided out of. Nothing is returned near the border or if the noise leaves no star.
+fn refine(values: &[f32], width: i32, height: i32, star: &Star, sigma: f32) -> Option<(f32, f32)> {
+    let radius = ((3.0 * sigma).ceil() as i32).min(MAXIMUM_RADIUS);
+    let (x, y) = (star.x.round() as i32, star.y.round() as i32);
+    if x < radius || y < radius || x >= width - radius || y >= height - radius {
+        return None;
+    }
+
+    let window = |dx: i32, dy: i32| {
+        let (ox, oy) = ((x + dx) as f32 - star.x, (y + dy) as f32 - star.y);
+        values[((y + dy) * width + x + dx) as usize] * (-(ox * ox + oy * oy) / (2.0 * sigma * sigma)).exp()
+    };
+    let mut sum = 0.0;
+    let (mut sx, mut sy) = (0.0, 0.0);
+    for dy in -radius..=radius {
+        for dx in -radius..=radius {
+            let weight = window(dx, dy);
+            sum += weight;
+            sx += weight * dx as f32;
+            sy += weight * dy as f32;
+        }
+    }
+    if sum <= 0.0 {
+        return None;
+    }
+    let (cx, cy) = (sx / sum, sy / sum);
+
+    let (mut xx, mut yy, mut xy) = (0.0, 0.0, 0.0);
+    for dy in -radius..=radius {
+        for dx in -radius..=radius {
+            let weight = window(dx, dy);
+            let (ox, oy) = (dx as f32 - cx, dy as f32 - cy);
+            xx += weight * ox * ox;
+            yy += weight * oy * oy;
+            xy += weight * ox * oy;
+        }
+    }
+    let (xx, yy, xy) = (xx / sum, yy / sum, xy / sum);
+
+    // The inverse moments of the star are those measured less those of the window.
+    let determinant = xx * yy - xy * xy;
+    if determinant <= 0.0 {
+        return None;
+    }
+    let (a, b, c) = (yy / determinant - 1.0 / (sigma * sigma), -xy / determinant, xx / determinant - 1.0 / (sigma * sigma));
+    let determinant = a * c - b * b;
+    if a <= 0.0 || determinant <= 0.0 {
+        return None;
+    }
+
+    Some(shape(c / determinant, a / determinant, -b / determinant))
+}
+
+// Finds the local maxima brighter than the background by the given number of standard deviations
+// and measures their centroid, flux and shape. The stars are sorted by decreasing flux.
+pub fn detect(image: &Image, sigma: f32) -> Vec<Star> {
+    let (width, height) = (image.width() as i32, image.height() as i32);
+    let values = flatten(&luminance(image), width as usize, height as usize);
+    let (_, noise) = background(&values);
+    let threshold = sigma * noise.max(f32::EPSILON);
+    let value = |x: i32, y: i32| values[(y * width + x) as usize];
+
+    let mut stars = (RADIUS..(height - RADIUS))
+        .into_par_iter()
+        .flat_map(|y| (RADIUS..(width - RADIUS))
+            .into_par_iter()
+            .map(move |x| (x, y))
+        )
+        .filter(|&(x, y)| {
+            let peak = value(x, y);
+            // Ties are broken by position, so that flat tops yield a single star.
+            peak > threshold && (-1..=1)
+                .flat_map(|dy| (-1..=1).map(move |dx| (dx, dy)))
+                .filter(|&offset| offset != (0, 0))
+                .all(|(dx, dy)| {
+                    let neighbour = value(x + dx, y + dy);
+                    neighbour < peak || (neighbour == peak && (dy, dx) > (0, 0))
+                })
+        })
+        .filter_map(|(x, y)| {
+            let mut sum = 0.0;
+            let (mut sx, mut sy) = (0.0, 0.0);
+            for dy in -RADIUS..=RADIUS {
+                for dx in -RADIUS..=RADIUS {
+                    let weight = value(x + dx, y + dy).max(0.0);
+                    sum += weight;
+                    sx += weight * dx as f32;
+                    sy += weight * dy as f32;
+                }
+            }
+            if sum <= 0.0 {
+                return None;
+            }
+            let (cx, cy) = (sx / sum, sy / sum);
+
+            // Second moments around the centroid.
+            let (mut xx, mut yy, mut xy) = (0.0, 0.0, 0.0);
+            for dy in -RADIUS..=RADIUS {
+                for dx in -RADIUS..=RADIUS {
+                    let weight = value(x + dx, y + dy).max(0.0);
+                    let (ox, oy) = (dx as f32 - cx, dy as f32 - cy);
+                    xx += weight * ox * ox;
+                    yy += weight * oy * oy;
+                    xy += weight * ox * oy;
+                }
+            }
+            let (fwhm, eccentricity) = shape(xx / sum, yy / sum, xy / sum);
+            // Single hot pixels and cosmic rays are narrower than any star.
+            if fwhm < 1.0 {
+                return None;
+            }
+
+            Some(Star {
+                x: x as f32 + cx,
+                y: y as f32 + cy,
+                flux: sum,
+                peak: value(x, y),
+                fwhm,
+                eccentricity,
+            })
+        })
+        .collect::<Vec<Star>>();
+
+    // The square cuts off the profiles of stars wider than itself, which makes them appear smaller
+    // and rounder than they are. Their shapes are measured again in a window sized to the median
+    // FWHM found in the square, where the window allows it.
+    if !stars.is_empty() {
+        let fwhm = statistics::median(&mut stars.iter().map(|star| star.fwhm).collect::<Vec<f32>>());
+        let window = WINDOW * fwhm / 2.3548;
+        stars.par_iter_mut().for_each(|star| {
+            if let Some((fwhm, eccentricity)) = refine(&values, width, height, star, window) {
+                star.fwhm = fwhm;
+                star.eccentricity = eccentricity;
+            }
+        });
+    }
+
+    stars.par_sort_by(|a, b| b.flux.partial_cmp(&a.flux).unwrap_or(Ordering::Equal));
+    stars
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Stars of the given FWHM on a grid, above a background with gaussian noise from a fixed seed.
+    fn field(size: u32, fwhm: f32) -> Image {
+        let sigma = fwhm / 2.3548;
+        let data = statistics::normal((size * size) as usize)
+            .iter()
+            .enumerate()
+            .map(|(i, normal)| {
+                let (x, y) = ((i as u32 % size) as f32, (i as u32 / size) as f32);
+                // The stars are 64 pixels apart and off the pixel centers.
+                let (dx, dy) = ((x - 32.3).rem_euclid(64.0) - 32.0, (y - 32.6).rem_euclid(64.0) - 32.0);
+                0.1 + 0.002 * normal + 0.5 * (-(dx * dx + dy * dy) / (2.0 * sigma * sigma)).exp()
+            })
+            .collect();
+        Image::from_raw(size, size, 1, data)
+    }
+
+    #[test]
+    fn wide_stars_are_measured_in_full() {
+        for &fwhm in &[2.5, 8.0, 14.0] {
+            let stars = detect(&field(256, fwhm), 5.0);
+            assert!(stars.len() >= 9, "{} stars of FWHM {}", stars.len(), fwhm);
+            for star in &stars {
+                assert!((star.fwhm - fwhm).abs() < 0.1 * fwhm, "FWHM {} measured as {}", fwhm, star.fwhm);
+                assert!(star.eccentricity < 0.3, "eccentricity {} for FWHM {}", star.eccentricity, fwhm);
+            }
+        }
+    }
+}
@@ -0,0 +1,227 @@
+use std::cmp::Ordering;
+
+// Returns the median of the values, reordering them in the process.
+pub fn median(values: &mut [f32]) -> f32 {
+    if values.is_empty() {
+        return 0.0;
+    }
+
+    let length = values.len();
+    let middle = length / 2;
+    let (lower, median, _) = values.select_nth_unstable_by(middle, |a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal));
+    let median = *median;
+
+    if length % 2 == 0 {
+        let below = lower.iter().cloned().fold(f32::NEG_INFINITY, f32::max);
+        (below + median) / 2.0
+    } else {
+        median
+    }
+}
+
+pub fn mean(values: &[f32]) -> f32 {
+    if values.is_empty() {
+        return 0.0;
+    }
+
+    values.iter().sum::<f32>() / values.len() as f32
+}
+
+pub fn standard_deviation(values: &[f32], mean: f32) -> f32 {
+    if values.len() < 2 {
+        return 0.0;
+    }
+
+    (values.iter().map(|value| (value - mean).powi(2)).sum::<f32>() / (values.len() - 1) as f32).sqrt()
+}
+
+// Median absolute deviation scaled to be a consistent estimator of the standard deviation.
+pub fn mad(values: &[f32], median: f32) -> f32 {
+    let mut deviations = values
+        .iter()
+        .map(|value| (value - median).abs())
+        .collect::<Vec<f32>>();
+
+    self::median(&mut deviations) * 1.4826
+}
+
+// Averages the values after iteratively rejecting outliers further than kappa standard deviations
+// from the median.
+pub fn sigma_clipped_mean(values: &mut Vec<f32>, low: f32, high: f32) -> f32 {
+    loop {
+        let center = median(values);
+        let sigma = standard_deviation(values, mean(values));
+        let length = values.len();
+
+        values.retain(|&value| value >= center - low * sigma && value <= center + high * sigma);
+
+        if values.len() == length || values.len() < 3 {
+            break;
+        }
+    }
+
+    mean(values)
+}
+
+// Averages the values after iteratively rejecting outliers like sigma_clipped_mean, but estimates
+// the standard deviation after clamping the values to 1.5 standard deviations around the median,
+// so that strong outliers do not inflate it.
+pub fn winsorized_sigma_clipped_mean(values: &mut Vec<f32>, low: f32, high: f32) -> f32 {
+    loop {
+        let center = median(values);
+        let mut sigma = standard_deviation(values, mean(values));
+        for _ in 0..10 {
+            let winsorized = values
+                .iter()
+                .map(|value| value.max(center - 1.5 * sigma).min(center + 1.5 * sigma))
+                .collect::<Vec<f32>>();
+            // Corrects for the variance that is lost by clamping a normal distribution.
+            let next = 1.134 * standard_deviation(&winsorized, mean(&winsorized));
+            let converged = (next - sigma).abs() <= 5e-4 * sigma;
+            sigma = next;
+            if converged {
+                break;
+            }
+        }
+        let length = values.len();
+
+        values.retain(|&value| value >= center - low * sigma && value <= center + high * sigma);
+
+        if values.len() == length || values.len() < 3 {
+            break;
+        }
+    }
+
+    mean(values)
+}
+
+// Averages the values after iteratively rejecting outliers from a straight line fitted to the
+// sorted values, measured in average absolute deviations from the line. This suits many values
+// with a spread that is not normal, such as frames taken under changing sky conditions.
+pub fn linear_fit_clipped_mean(values: &mut Vec<f32>, low: f32, high: f32) -> f32 {
+    loop {
+        let length = values.len();
+        if length < 3 {
+            break;
+        }
+        values.sort_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal));
+
+        // Least squares fit of value = offset + slope * index.
+        let center = (length - 1) as f32 / 2.0;
+        let average = mean(values);
+        let (covariance, variance) = values
+            .iter()
+            .enumerate()
+            .fold((0.0, 0.0), |(covariance, variance), (i, value)| {
+                let x = i as f32 - center;
+                (covariance + x * (value - average), variance + x * x)
+            });
+        let slope = covariance / variance;
+        let fit = |i: usize| average + slope * (i as f32 - center);
+        let sigma = values
+            .iter()
+            .enumerate()
+            .map(|(i, value)| (value - fit(i)).abs())
+            .sum::<f32>() / length as f32;
+
+        let kept = values
+            .iter()
+            .enumerate()
+            .filter(|&(i, &value)| value >= fit(i) - low * sigma && value <= fit(i) + high * sigma)
+            .map(|(_, &value)| value)
+            .collect::<Vec<f32>>();
+        *values = kept;
+
+        if values.len() == length {
+            break;
+        }
+    }
+
+    mean(values)
+}
+
+// Values of a standard normal distribution from a fixed seed, as noise in the tests of the
+// operations.
+#[cfg(test)]
+pub fn normal(count: usize) -> Vec<f32> {
+    let mut state = 12345u64;
+    let mut uniform = move || {
+        state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+        ((state >> 11) as f64 / (1u64 << 53) as f64).max(f64::MIN_POSITIVE)
+    };
+    (0..count)
+        .map(|_| ((-2.0 * uniform().ln()).sqrt() * (2.0 * std::f64::consts::PI * uniform()).cos()) as f32)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Twenty values around 10 with a spread of about 0.1.
+    fn values() -> Vec<f32> {
+        (0..20).map(|i| 10.0 + ((i * 7) % 5) as f32 * 0.05 - 0.1).collect()
+    }
+
+    fn close(value: f32, expected: f32) -> bool {
+        (value - expected).abs() < 0.02
+    }
+
+    #[test]
+    fn median_of_odd_and_even_counts() {
+        assert_eq!(median(&mut vec![3.0, 1.0, 2.0]), 2.0);
+        assert_eq!(median(&mut vec![4.0, 1.0, 3.0, 2.0]), 2.5);
+        assert_eq!(median(&mut Vec::new()), 0.0);
+    }
+
+    #[test]
+    fn mad_is_scaled_to_the_standard_deviation() {
+        let values = [-1.0, 1.0, -1.0, 1.0, 0.0];
+        assert!((mad(&values, 0.0) - 1.4826).abs() < 1e-4);
+    }
+
+    #[test]
+    fn sigma_clipping_rejects_a_single_outlier() {
+        let mut high = values();
+        high.push(100.0);
+        assert!(close(sigma_clipped_mean(&mut high, 3.0, 3.0), 10.0));
+        assert_eq!(high.len(), 20);
+
+        let mut low = values();
+        low.push(-80.0);
+        assert!(close(sigma_clipped_mean(&mut low, 3.0, 3.0), 10.0));
+        assert_eq!(low.len(), 20);
+    }
+
+    #[test]
+    fn thresholds_reject_only_their_side() {
+        let mut values = values();
+        values.push(100.0);
+        values.push(-80.0);
+        // Without a lower threshold, the low outlier is kept.
+        let mean = sigma_clipped_mean(&mut values, 1000.0, 2.0);
+        assert!(values.contains(&-80.0) && !values.contains(&100.0));
+        assert!(mean < 9.0);
+    }
+
+    #[test]
+    fn winsorizing_rejects_outliers_that_inflate_the_deviation() {
+        // Several strong outliers inflate the plain standard deviation so much that none is rejected.
+        let mut outliers = values();
+        outliers.extend_from_slice(&[60.0, 65.0, 70.0, 75.0]);
+        let mut plain = outliers.clone();
+        assert!(sigma_clipped_mean(&mut plain, 3.0, 3.0) > 15.0);
+
+        assert!(close(winsorized_sigma_clipped_mean(&mut outliers, 3.0, 3.0), 10.0));
+        assert_eq!(outliers.len(), 20);
+    }
+
+    #[test]
+    fn linear_fit_clipping_rejects_values_off_the_line() {
+        // A steady trend, as from a changing sky, with one outlier.
+        let mut values = (0..20).map(|i| 10.0 + i as f32 * 0.1).collect::<Vec<f32>>();
+        values.push(50.0);
+        assert!(close(linear_fit_clipped_mean(&mut values, 3.0, 3.0), 10.95));
+        assert_eq!(values.len(), 20);
+    }
+}
@@ -0,0 +1,290 @@
+use super::{
+    Layer,
+    buffer::Image,
+    stars,
+    statistics,
+    transform::Transform,
+};
+use rayon::prelude::*;
+
+pub use super::multiband::Multiband;
+pub use super::drizzle::Drizzle;
+
+// The value of one channel of a layer at a pixel of the joined image.
+#[derive(Clone, Copy, Debug)]
+pub struct Sample {
+    pub value: f32,
+    // Weight of the whole layer, see Strategy::weights.
+    pub weight: f32,
+    // Distance to the nearest edge of the layer in pixels.
+    pub edge: f32,
+}
+
+// A layer resampled onto the canvas of the joined image, within the bounding box of the layer.
+pub struct Resampled {
+    // Black where the layer does not cover the canvas.
+    pub image: Image,
+    // Distance to the nearest edge of the layer for each pixel, negative infinity outside.
+    pub edge: Vec<f32>,
+    // Position of the bounding box on the canvas.
+    pub origin: (usize, usize),
+    // Weight of the whole layer, see Strategy::weights.
+    pub weight: f32,
+    // Maps the pixel coordinates of the layer to those of the canvas.
+    pub transform: Transform,
+}
+
+impl Resampled {
+    // Distance to the nearest edge of the layer at the given pixel of the canvas, negative infinity
+    // outside of the layer.
+    pub fn edge_at(&self, x: usize, y: usize) -> f32 {
+        let (width, height) = (self.image.width() as usize, self.image.height() as usize);
+        if x < self.origin.0 || y < self.origin.1 || x >= self.origin.0 + width || y >= self.origin.1 + height {
+            return f32::NEG_INFINITY;
+        }
+        self.edge[(y - self.origin.1) * width + x - self.origin.0]
+    }
+}
+
+// Combines the samples of all layers that cover a pixel into the value of the joined image.
+pub trait Strategy: Send + Sync {
+    // Description of the strategy in progress messages.
+    fn name(&self) -> String;
+
+    // Weights of the layers, computed once before joining. All layers are weighted equally by default.
+    fn weights(&self, layers: &[Layer]) -> Vec<f32> {
+        vec![1.0; layers.len()]
+    }
+
+    // Resolution of the joined image relative to the reference, in pixels per pixel.
+    fn scale(&self) -> f32 {
+        1.0
+    }
+
+    // Combines the samples, there is at least one.
+    fn combine(&self, samples: &mut Vec<Sample>) -> f32;
+
+    // Strategies that compare the values of different layers, such as to reject outliers, return
+    // true to get the layers normalized to the background level and noise of the reference first.
+    fn normalize(&self) -> bool {
+        false
+    }
+
+    // Strategies that drop the pixels of the layers onto the canvas, instead of sampling the layers
+    // at each pixel of the canvas, return the joined image of the given size together with a map of
+    // the total weight that went into each pixel. The canvas transform maps the coordinates of the
+    // reference to those of the joined image.
+    fn scatter(&self, _layers: &[Layer], _weights: &[f32], _canvas: &Transform, _size: (u32, u32), _channels: usize) -> Option<(Image, Image)> {
+        None
+    }
+
+    // Strategies that blend more than single pixels, such as multi-band blending, return the joined
+    // image of the given size. They get the layers resampled onto the canvas by the given function
+    // of the index of the layer, one at a time.
+    fn blend(&self, _layers: &[Layer], _resample: &(dyn Fn(usize) -> Resampled + Sync), _size: (u32, u32)) -> Option<Image> {
+        None
+    }
+}
+
+// The built-in strategies.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mode {
+    Mosaic,
+    Multiband,
+    Stack,
+    Weighted,
+    Maximum,
+    Minimum,
+    Drizzle,
+}
+
+impl Mode {
+    pub fn parse(name: &str) -> Option<Mode> {
+        match name.to_lowercase().as_str() {
+            "mosaic" => Some(Mode::Mosaic),
+            "multiband" => Some(Mode::Multiband),
+            "stack" => Some(Mode::Stack),
+            "weighted" => Some(Mode::Weighted),
+            "maximum" => Some(Mode::Maximum),
+            "minimum" => Some(Mode::Minimum),
+            "drizzle" => Some(Mode::Drizzle),
+            _ => None
+        }
+    }
+}
+
+impl Default for Mode {
+    fn default() -> Mode {
+        Mode::Mosaic
+    }
+}
+
+// Blends overlapping panels of a mosaic, weighting each one by the distance to its edges so that
+// the seams fade smoothly.
+pub struct Mosaic;
+
+impl Strategy for Mosaic {
+    fn name(&self) -> String {
+        "mosaic feathering".to_string()
+    }
+
+    fn combine(&self, samples: &mut Vec<Sample>) -> f32 {
+        let (sum, weights) = samples
+            .iter()
+            .fold((0.0, 0.0), |(sum, weights), sample| {
+                // Half a pixel keeps samples on the very edge from vanishing where only they exist.
+                let weight = sample.edge.max(0.5);
+                (sum + sample.value * weight, weights + weight)
+            });
+        sum / weights
+    }
+}
+
+// How the values of the stacked layers at one pixel are combined into one value.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Integration {
+    Average,
+    Median,
+    // Rejects values beyond the thresholds in standard deviations from the median.
+    KappaSigma,
+    // Like kappa-sigma, with a standard deviation that is robust against the outliers themselves.
+    WinsorizedSigma,
+    // Rejects values beyond the thresholds in deviations from a line fitted to the sorted values,
+    // best for large numbers of frames.
+    LinearFit,
+}
+
+impl Integration {
+    pub fn parse(name: &str) -> Option<Integration> {
+        match name.to_lowercase().as_str() {
+            "average" => Some(Integration::Average),
+            "median" => Some(Integration::Median),
+            "kappa-sigma" => Some(Integration::KappaSigma),
+            "winsorized-sigma" => Some(Integration::WinsorizedSigma),
+            "linear-fit" => Some(Integration::LinearFit),
+            _ => None
+        }
+    }
+
+    // Combines the values, rejecting those too low or too high by the given thresholds.
+    fn combine(&self, values: &mut Vec<f32>, low: f32, high: f32) -> f32 {
+        match self {
+            Integration::Average => statistics::mean(values),
+            Integration::Median => statistics::median(values),
+            Integration::KappaSigma => statistics::sigma_clipped_mean(values, low, high),
+            Integration::WinsorizedSigma => statistics::winsorized_sigma_clipped_mean(values, low, high),
+            Integration::LinearFit => statistics::linear_fit_clipped_mean(values, low, high),
+        }
+    }
+}
+
+impl Default for Integration {
+    fn default() -> Integration {
+        Integration::KappaSigma
+    }
+}
+
+// Stacks aligned frames of the same field, which removes satellite trails, planes and cosmic rays
+// that are only present in a few of them.
+pub struct Stack {
+    integration: Integration,
+    // Rejection thresholds below and above the center of the values.
+    low: f32,
+    high: f32,
+}
+
+impl Stack {
+    pub fn new(integration: Integration) -> Stack {
+        Stack {
+            integration,
+            low: 3.0,
+            high: 3.0,
+        }
+    }
+
+    // Sets the thresholds below and above the center at which values are rejected, in standard
+    // deviations or in deviations from the fitted line.
+    pub fn low(mut self, low: f32) -> Stack {
+        self.low = low;
+        self
+    }
+
+    pub fn high(mut self, high: f32) -> Stack {
+        self.high = high;
+        self
+    }
+}
+
+impl Strategy for Stack {
+    fn name(&self) -> String {
+        format!("{:?} stacking", self.integration)
+    }
+
+    fn combine(&self, samples: &mut Vec<Sample>) -> f32 {
+        let mut values = samples
+            .iter()
+            .map(|sample| sample.value)
+            .collect::<Vec<f32>>();
+        self.integration.combine(&mut values, self.low, self.high)
+    }
+
+    // Frames taken at a different sky brightness or transparency would otherwise be rejected as
+    // a whole instead of their outliers.
+    fn normalize(&self) -> bool {
+        true
+    }
+}
+
+// Stacks frames of different quality, weighting each by the inverse variance of its background
+// noise, so that frames taken through haze or at a higher sky brightness contribute less.
+pub struct Weighted;
+
+impl Strategy for Weighted {
+    fn name(&self) -> String {
+        "noise weighted stacking".to_string()
+    }
+
+    fn weights(&self, layers: &[Layer]) -> Vec<f32> {
+        layers
+            .par_iter()
+            .map(|layer| {
+                let (_, noise) = stars::background(&stars::luminance(&layer.image));
+                if noise > 0.0 { 1.0 / (noise * noise) } else { 1.0 }
+            })
+            .collect()
+    }
+
+    fn combine(&self, samples: &mut Vec<Sample>) -> f32 {
+        let (sum, weights) = samples
+            .iter()
+            .fold((0.0, 0.0), |(sum, weights), sample| (sum + sample.value * sample.weight, weights + sample.weight));
+        sum / weights
+    }
+}
+
+// Keeps the brightest value of each pixel, which draws star trails from a sequence of frames.
+pub struct Maximum;
+
+impl Strategy for Maximum {
+    fn name(&self) -> String {
+        "maximum".to_string()
+    }
+
+    fn combine(&self, samples: &mut Vec<Sample>) -> f32 {
+        samples.iter().map(|sample| sample.value).fold(f32::NEG_INFINITY, f32::max)
+    }
+}
+
+// Keeps the darkest value of each pixel, which removes moving lights such as planes from frames of a
+// fixed scene.
+pub struct Minimum;
+
+impl Strategy for Minimum {
+    fn name(&self) -> String {
+        "minimum".to_string()
+    }
+
+    fn combine(&self, samples: &mut Vec<Sample>) -> f32 {
+        samples.iter().map(|sample| sample.value).fold(f32::INFINITY, f32::min)
+    }
+}
@@ -0,0 +1,390 @@
+use nalgebra::{
+    DMatrix,
+    Matrix3,
+    Vector2,
+    Vector3,
+};
+
+pub type Point = Vector2<f32>;
+
+// Degrees of freedom of the transform between two frames.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Model {
+    // Shift only.
+    Translation,
+    // Shift, rotation and uniform scale, this covers field rotation and meridian flips.
+    Similarity,
+    // Additionally shear and non-uniform scale, for example from different optics.
+    Affine,
+    // Projective transform with eight degrees of freedom, for wide-field mosaic panels.
+    Homography,
+}
+
+impl Model {
+    pub fn parse(name: &str) -> Option<Model> {
+        match name.to_lowercase().as_str() {
+            "translation" => Some(Model::Translation),
+            "similarity" => Some(Model::Similarity),
+            "affine" => Some(Model::Affine),
+            "homography" => Some(Model::Homography),
+            _ => None
+        }
+    }
+
+    // Minimum number of point pairs that determine a transform.
+    pub fn samples(&self) -> usize {
+        match self {
+            Model::Translation => 1,
+            Model::Similarity => 2,
+            Model::Affine => 3,
+            Model::Homography => 4,
+        }
+    }
+}
+
+impl Default for Model {
+    fn default() -> Model {
+        Model::Similarity
+    }
+}
+
+// A transform of the plane in homogeneous coordinates, which maps the pixel coordinates of a layer
+// to the coordinates of the joined image. The inverse is kept to resample layers quickly.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Transform {
+    matrix: Matrix3<f32>,
+    inverse: Matrix3<f32>,
+}
+
+impl Transform {
+    // Returns none if the matrix cannot be inverted.
+    pub fn from_matrix(matrix: Matrix3<f32>) -> Option<Transform> {
+        let inverse = matrix.try_inverse()?;
+        Some(Transform {
+            matrix,
+            inverse,
+        })
+    }
+
+    pub fn identity() -> Transform {
+        Transform {
+            matrix: Matrix3::identity(),
+            inverse: Matrix3::identity(),
+        }
+    }
+
+    pub fn translation(x: f32, y: f32) -> Transform {
+        Transform {
+            matrix: Matrix3::new(
+                1.0, 0.0, x,
+                0.0, 1.0, y,
+                0.0, 0.0, 1.0,
+            ),
+            inverse: Matrix3::new(
+                1.0, 0.0, -x,
+                0.0, 1.0, -y,
+                0.0, 0.0, 1.0,
+            ),
+        }
+    }
+
+    // Rotates by the angle in radians around the origin and scales uniformly, then translates.
+    pub fn similarity(scale: f32, angle: f32, x: f32, y: f32) -> Transform {
+        let (sin, cos) = (scale * angle.sin(), scale * angle.cos());
+        Transform::from_matrix(Matrix3::new(
+            cos, -sin, x,
+            sin, cos, y,
+            0.0, 0.0, 1.0,
+        )).unwrap_or_else(Transform::identity)
+    }
+
+    // Rotates a frame of the given size by 180 degrees around its center, as happens after a meridian flip.
+    pub fn flip(width: u32, height: u32) -> Transform {
+        Transform::similarity(1.0, std::f32::consts::PI, width as f32 - 1.0, height as f32 - 1.0)
+    }
+
+    pub fn matrix(&self) -> &Matrix3<f32> {
+        &self.matrix
+    }
+
+    pub fn inverse(&self) -> Transform {
+        Transform {
+            matrix: self.inverse,
+            inverse: self.matrix,
+        }
+    }
+
+    // Returns the transform that applies this transform first and then the other one.
+    pub fn then(&self, other: &Transform) -> Transform {
+        Transform {
+            matrix: other.matrix * self.matrix,
+            inverse: self.inverse * other.inverse,
+        }
+    }
+
+    fn map(matrix: &Matrix3<f32>, point: &Point) -> Point {
+        let result = matrix * Vector3::new(point.x, point.y, 1.0);
+        Point::new(result.x / result.z, result.y / result.z)
+    }
+
+    pub fn apply(&self, point: &Point) -> Point {
+        Transform::map(&self.matrix, point)
+    }
+
+    pub fn apply_inverse(&self, point: &Point) -> Point {
+        Transform::map(&self.inverse, point)
+    }
+
+    // The translation, the rotation in degrees and the average scale of the transform.
+    pub fn offset(&self) -> Point {
+        self.apply(&Point::zeros())
+    }
+
+    pub fn rotation(&self) -> f32 {
+        self.matrix[(1, 0)].atan2(self.matrix[(0, 0)]).to_degrees()
+    }
+
+    pub fn scale(&self) -> f32 {
+        (self.matrix[(0, 0)] * self.matrix[(1, 1)] - self.matrix[(0, 1)] * self.matrix[(1, 0)]).abs().sqrt()
+    }
+
+    pub fn is_identity(&self) -> bool {
+        (self.matrix - Matrix3::identity()).iter().all(|value| value.abs() < 1e-6)
+    }
+
+    // Whether the transform maps a frame of the given size without folding or mirroring it, and
+    // without changing the area around any corner by more than the given factor relative to the
+    // center. The projective divisor must stay positive, it is linear, so checking the corners
+    // suffices.
+    pub fn is_regular(&self, width: u32, height: u32, factor: f32) -> bool {
+        let matrix = self.matrix.map(|value| value as f64);
+        let determinant = matrix.determinant();
+        // The local change of area of a projective transform is its determinant divided by the
+        // cube of the divisor.
+        let area = |x: f64, y: f64| {
+            let w = matrix[(2, 0)] * x + matrix[(2, 1)] * y + matrix[(2, 2)];
+            if w > 0.0 { determinant / w.powi(3) } else { f64::NAN }
+        };
+        let (w, h) = (width as f64, height as f64);
+        let center = area(w / 2.0, h / 2.0);
+        let factor = factor as f64;
+        center > 0.0 && [(0.0, 0.0), (w, 0.0), (0.0, h), (w, h)]
+            .iter()
+            .all(|&(x, y)| {
+                let ratio = area(x, y) / center;
+                ratio >= 1.0 / factor && ratio <= factor
+            })
+    }
+
+    // Returns the bounding box of a frame of the given size after transforming it. The box runs
+    // along the outer edges of the pixels, half a pixel beyond their centers.
+    pub fn bounds(&self, width: u32, height: u32) -> (Point, Point) {
+        let (w, h) = (width as f32 - 0.5, height as f32 - 0.5);
+        [Point::new(-0.5, -0.5), Point::new(w, -0.5), Point::new(-0.5, h), Point::new(w, h)]
+            .iter()
+            .map(|corner| self.apply(corner))
+            .fold(
+                (Point::repeat(f32::INFINITY), Point::repeat(f32::NEG_INFINITY)),
+                |(min, max), point| (min.inf(&point), max.sup(&point))
+            )
+    }
+
+    // Estimates the transform of the given model that maps the second points of the pairs onto
+    // the first ones in the least squares sense. Returns none for degenerate configurations.
+    pub fn estimate(model: Model, pairs: &[(Point, Point)]) -> Option<Transform> {
+        if pairs.len() < model.samples() {
+            return None;
+        }
+        let count = pairs.len() as f32;
+        let (mean_a, mean_b) = pairs
+            .iter()
+            .fold((Point::zeros(), Point::zeros()), |(sa, sb), (a, b)| (sa + a, sb + b));
+        let (mean_a, mean_b) = (mean_a / count, mean_b / count);
+
+        match model {
+            Model::Translation => {
+                let offset = mean_a - mean_b;
+                Some(Transform::translation(offset.x, offset.y))
+            },
+            Model::Similarity => {
+                // The closed form solution of Umeyama for two dimensions.
+                let (mut dot, mut cross, mut norm) = (0.0, 0.0, 0.0);
+                for (a, b) in pairs {
+                    let (a, b) = (a - mean_a, b - mean_b);
+                    dot += b.x * a.x + b.y * a.y;
+                    cross += b.x * a.y - b.y * a.x;
+                    norm += b.norm_squared();
+                }
+                if norm <= f32::EPSILON {
+                    return None;
+                }
+
+                let angle = cross.atan2(dot);
+                let scale = dot.hypot(cross) / norm;
+                let rotated = Transform::similarity(scale, angle, 0.0, 0.0).apply(&mean_b);
+                let offset = mean_a - rotated;
+                Some(Transform::similarity(scale, angle, offset.x, offset.y))
+            },
+            Model::Homography => Transform::homography(pairs),
+            Model::Affine => {
+                // Normal equations of the centered points, solved for both rows of the matrix.
+                let (mut xx, mut xy, mut yy) = (0.0, 0.0, 0.0);
+                let (mut ax, mut ay) = (Vector2::zeros(), Vector2::zeros());
+                for (a, b) in pairs {
+                    let (a, b) = (a - mean_a, b - mean_b);
+                    xx += b.x * b.x;
+                    xy += b.x * b.y;
+                    yy += b.y * b.y;
+                    ax += b * a.x;
+                    ay += b * a.y;
+                }
+                let determinant = xx * yy - xy * xy;
+                if determinant.abs() <= f32::EPSILON {
+                    return None;
+                }
+                let solve = |v: Vector2<f32>| Vector2::new(
+                    (yy * v.x - xy * v.y) / determinant,
+                    (xx * v.y - xy * v.x) / determinant,
+                );
+                let (row_x, row_y) = (solve(ax), solve(ay));
+
+                Transform::from_matrix(Matrix3::new(
+                    row_x.x, row_x.y, mean_a.x - row_x.dot(&mean_b),
+                    row_y.x, row_y.y, mean_a.y - row_y.dot(&mean_b),
+                    0.0, 0.0, 1.0,
+                ))
+            },
+        }
+    }
+
+    // Estimates a homography with the normalized direct linear transform. The points are moved
+    // to their centroid and scaled to an average distance of the square root of two, which keeps
+    // the linear system well conditioned.
+    fn homography(pairs: &[(Point, Point)]) -> Option<Transform> {
+        let normalization = |points: Vec<Point>| {
+            let count = points.len() as f64;
+            let (mx, my) = points
+                .iter()
+                .fold((0.0, 0.0), |(x, y), point| (x + point.x as f64 / count, y + point.y as f64 / count));
+            let distance = points
+                .iter()
+                .map(|point| (point.x as f64 - mx).hypot(point.y as f64 - my))
+                .sum::<f64>() / count;
+            let scale = if distance > 0.0 { 2.0_f64.sqrt() / distance } else { 1.0 };
+            Matrix3::new(
+                scale, 0.0, -scale * mx,
+                0.0, scale, -scale * my,
+                0.0, 0.0, 1.0,
+            )
+        };
+        let normalize = |matrix: &Matrix3<f64>, point: &Point| {
+            let point = matrix * Vector3::new(point.x as f64, point.y as f64, 1.0);
+            (point.x, point.y)
+        };
+
+        let ta = normalization(pairs.iter().map(|(a, _)| *a).collect());
+        let tb = normalization(pairs.iter().map(|(_, b)| *b).collect());
+
+        // Every pair contributes two rows to the system A h = 0.
+        let mut rows = Vec::with_capacity(pairs.len() * 18);
+        for (a, b) in pairs {
+            let ((u, v), (x, y)) = (normalize(&ta, a), normalize(&tb, b));
+            rows.extend_from_slice(&[-x, -y, -1.0, 0.0, 0.0, 0.0, u * x, u * y, u]);
+            rows.extend_from_slice(&[0.0, 0.0, 0.0, -x, -y, -1.0, v * x, v * y, v]);
+        }
+        let a = DMatrix::from_row_slice(pairs.len() * 2, 9, &rows);
+
+        // The solution is the eigenvector of the smallest eigenvalue of the normal matrix.
+        let eigen = (a.transpose() * a).symmetric_eigen();
+        let smallest = eigen.eigenvalues
+            .iter()
+            .enumerate()
+            .min_by(|a, b| a.1.partial_cmp(b.1).unwrap_or(std::cmp::Ordering::Equal))?
+            .0;
+        let h = eigen.eigenvectors.column(smallest);
+        let normalized = Matrix3::new(
+            h[0], h[1], h[2],
+            h[3], h[4], h[5],
+            h[6], h[7], h[8],
+        );
+
+        let matrix = ta.try_inverse()? * normalized * tb;
+        if matrix[(2, 2)].abs() <= f64::EPSILON {
+            return None;
+        }
+        Transform::from_matrix((matrix / matrix[(2, 2)]).map(|value| value as f32))
+    }
+}
+
+impl Default for Transform {
+    fn default() -> Transform {
+        Transform::identity()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: &Point, b: &Point) -> bool {
+        (a - b).norm() < 1e-3
+    }
+
+    #[test]
+    fn then_applies_this_transform_first() {
+        let rotation = Transform::similarity(2.0, std::f32::consts::FRAC_PI_2, 0.0, 0.0);
+        let translation = Transform::translation(10.0, 5.0);
+        let point = Point::new(1.0, 0.0);
+
+        // Scaling and rotating (1, 0) gives (0, 2), which is then moved to (10, 7).
+        assert!(close(&rotation.then(&translation).apply(&point), &Point::new(10.0, 7.0)));
+        assert!(close(&translation.then(&rotation).apply(&point), &Point::new(-10.0, 22.0)));
+    }
+
+    #[test]
+    fn inverse_undoes_the_transform() {
+        let transform = Transform::similarity(1.5, 0.3, -4.0, 7.0)
+            .then(&Transform::from_matrix(Matrix3::new(
+                1.0, 0.1, 2.0,
+                -0.05, 0.9, 3.0,
+                1e-4, -2e-4, 1.0,
+            )).unwrap());
+        let point = Point::new(120.0, -35.0);
+
+        assert!(close(&transform.apply_inverse(&transform.apply(&point)), &point));
+        assert!(close(&transform.inverse().apply(&transform.apply(&point)), &point));
+        assert!(transform.then(&transform.inverse()).is_identity());
+    }
+
+    #[test]
+    fn flip_rotates_around_the_center() {
+        let flip = Transform::flip(100, 50);
+
+        assert!(close(&flip.apply(&Point::new(0.0, 0.0)), &Point::new(99.0, 49.0)));
+        assert!(close(&flip.apply(&Point::new(49.5, 24.5)), &Point::new(49.5, 24.5)));
+        assert!((flip.rotation().abs() - 180.0).abs() < 1e-3);
+        assert!(close(&flip.then(&flip).apply(&Point::new(3.0, 4.0)), &Point::new(3.0, 4.0)));
+    }
+
+    #[test]
+    fn degenerate_homographies_are_not_regular() {
+        let tilted = |h: f32| Transform::from_matrix(Matrix3::new(
+            1.0, 0.0, 0.0,
+            0.0, 1.0, 0.0,
+            h, 0.0, 1.0,
+        )).unwrap();
+
+        assert!(Transform::similarity(1.2, 0.5, 30.0, -20.0).is_regular(100, 100, 4.0));
+        assert!(tilted(1e-3).is_regular(100, 100, 4.0));
+        // The divisor becomes negative inside the frame, which folds it over the horizon.
+        assert!(!tilted(-0.02).is_regular(100, 100, 4.0));
+        // The area far from the center is shrunk by much more than the factor.
+        assert!(!tilted(0.03).is_regular(100, 100, 4.0));
+        // Mirrored.
+        assert!(!Transform::from_matrix(Matrix3::new(
+            -1.0, 0.0, 0.0,
+            0.0, 1.0, 0.0,
+            0.0, 0.0, 1.0,
+        )).unwrap().is_regular(100, 100, 4.0));
+    }
+}